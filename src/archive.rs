@@ -0,0 +1,908 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Error, Result, anyhow};
+
+use log::debug;
+
+use sha2::{Digest, Sha256};
+
+use console::Style;
+
+use indicatif::{DecimalBytes, ProgressBar};
+
+use tar::{Archive, Entry, EntryType};
+
+use fatfs::{Dir, ReadWriteSeek};
+
+use crate::checksum;
+use crate::disk;
+use crate::fat32;
+use crate::interrupt;
+use crate::journal::ExtractionJournal;
+
+// Size of a tar block: headers and file contents are padded to a multiple of this size
+const BLOCK_SIZE: u64 = 512;
+
+// Report of the structural validation of a tar archive
+#[derive(Debug, Default)]
+pub struct ArchiveReport {
+    // Number of files and directories in the archive
+    pub entry_count: u64,
+    // Sum of the sizes of files in the archive, i.e. the space required to extract it
+    pub total_size: u64,
+    pub issues: Vec<ArchiveIssue>,
+}
+
+#[derive(Debug)]
+pub struct ArchiveIssue {
+    // Path of the offending entry, or position in the archive when the header could not be read
+    pub entry: String,
+    pub message: String,
+}
+
+impl ArchiveReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn add_issue(&mut self, entry: String, message: &str) {
+        debug!("Invalid entry {entry}: {message}");
+        self.issues.push(ArchiveIssue {
+            entry,
+            message: message.to_string(),
+        });
+    }
+
+    pub fn print(&self) {
+        let cyan = Style::new().cyan();
+        let red = Style::new().red();
+        println!(
+            "Entries: {}   Extracted size: {}",
+            cyan.apply_to(self.entry_count),
+            cyan.apply_to(DecimalBytes(self.total_size))
+        );
+        for issue in &self.issues {
+            println!(
+                "{} {}: {}",
+                red.apply_to("[invalid]"),
+                issue.entry,
+                issue.message
+            );
+        }
+    }
+}
+
+// Walk every entry header of a tar archive, without extracting anything, to detect archives that would fail
+// to extract: truncated archive, corrupted headers, unsupported entry types, paths outside of the destination, and
+// files that cannot be stored on FAT32 (too large, invalid names, paths too long, names differing only by case)
+pub fn validate(filename: &str) -> Result<ArchiveReport, Error> {
+    let tar_file =
+        File::open(filename).with_context(|| format!("Failed to open archive {filename}"))?;
+    let tar_file_size = tar_file
+        .metadata()
+        .context("Failed to get tar file metadata")?
+        .len();
+
+    let mut report = ArchiveReport::default();
+    // End of the last entry read, including padding
+    let mut end_position = 0;
+    // Paths of the entries, by upper case path
+    let mut paths: HashMap<String, String> = HashMap::new();
+
+    let mut ar = Archive::new(BufReader::new(tar_file));
+    // Seeking over file contents, only headers are read
+    let entries = ar
+        .entries_with_seek()
+        .context("Failed to read tar entries")?;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Header checksum mismatch, or header truncated at the end of the archive
+                report.add_issue(
+                    format!("entry after offset {end_position}"),
+                    &format!("Invalid header, archive is corrupted or truncated ({e})"),
+                );
+                return Ok(report);
+            }
+        };
+        let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+
+        let size = entry.size();
+        end_position = entry.raw_file_position() + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        if entry.raw_file_position() + size > tar_file_size {
+            report.add_issue(
+                path,
+                "Content goes beyond the end of the archive, archive is truncated",
+            );
+            return Ok(report);
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                report.total_size += size;
+                if let Some(message) = fat32::check_file_size(size) {
+                    report.add_issue(path.clone(), &message);
+                }
+            }
+            EntryType::Directory => {}
+            // Global pax extensions do not describe any file
+            EntryType::XGlobalHeader => continue,
+            entry_type => {
+                report.add_issue(
+                    path.clone(),
+                    &format!("Unsupported entry type {entry_type:?}, only regular files and directories are allowed"),
+                );
+            }
+        }
+        report.entry_count += 1;
+
+        if let Some(message) = check_path(&path) {
+            report.add_issue(path, message);
+            continue;
+        }
+        if let Some(message) = fat32::check_path(Path::new(&path)) {
+            report.add_issue(path.clone(), &message);
+        }
+        // Names are case-insensitive on FAT32
+        let normalized_path = normalize_path(&path);
+        let key = normalized_path.to_uppercase();
+        match paths.get(&key) {
+            Some(other_path) if *other_path != normalized_path => {
+                report.add_issue(
+                    path,
+                    &format!("Same name as {other_path} on FAT32, names being case-insensitive"),
+                );
+            }
+            Some(_) => {}
+            None => {
+                paths.insert(key, normalized_path);
+            }
+        }
+    }
+
+    // A tar archive ends with (at least) two empty blocks
+    if tar_file_size < end_position + 2 * BLOCK_SIZE {
+        report.add_issue(
+            format!("end of archive at offset {end_position}"),
+            "No end-of-archive marker, archive is probably truncated",
+        );
+    }
+
+    debug!("Archive {filename} validated: {report:?}");
+    Ok(report)
+}
+
+// Path of an entry without its leading "./" and trailing "/", e.g. "dir" for "./dir/"
+fn normalize_path(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Check that a path is relative to the extraction destination
+fn check_path(path: &str) -> Option<&'static str> {
+    for component in Path::new(path).components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return Some("Absolute path"),
+            Component::ParentDir => return Some("Path goes outside of the destination (..)"),
+            Component::CurDir | Component::Normal(_) => {}
+        }
+    }
+    None
+}
+
+// File of an archive, with the size and SHA-256 of its content in the archive
+#[derive(Debug)]
+pub struct ArchiveFile {
+    // Relative to the extraction destination
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+// Extracted file whose content on the destination does not match the archive
+#[derive(Debug)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+// Extract a tar archive to destination, only allowing regular files and directories below destination.
+// Unlike tar::Archive::unpack, links, device nodes, permissions and names that FAT32 cannot store are refused.
+// When interrupted, extraction stops between two entries, so that no file is left half-written.
+// With a journal, files extracted by a previous attempt are skipped, and every extracted file is recorded.
+// Returns the files of the archive, to verify the extraction.
+pub fn extract<R: Read>(
+    reader: R,
+    destination: &Path,
+    journal: Option<&mut ExtractionJournal>,
+) -> Result<Vec<ArchiveFile>, Error> {
+    extract_entries(reader, destination, journal, None)
+}
+
+// Extract only the given files of a tar archive to destination, e.g. files found corrupted after extraction
+pub fn extract_files<R: Read>(
+    reader: R,
+    destination: &Path,
+    paths: &HashSet<PathBuf>,
+) -> Result<Vec<ArchiveFile>, Error> {
+    extract_entries(reader, destination, None, Some(paths))
+}
+
+fn extract_entries<R: Read>(
+    reader: R,
+    destination: &Path,
+    mut journal: Option<&mut ExtractionJournal>,
+    only: Option<&HashSet<PathBuf>>,
+) -> Result<Vec<ArchiveFile>, Error> {
+    let mut files = Vec::new();
+    let mut ar = Archive::new(reader);
+    let mut last_extracted = None;
+    for (extracted_count, entry) in ar
+        .entries()
+        .context("Failed to read tar entries")?
+        .enumerate()
+    {
+        if interrupt::is_interrupted() {
+            let message = match last_extracted {
+                Some(path) => format!("{extracted_count} entries extracted, last one being {path}"),
+                None => "no entry extracted".to_string(),
+            };
+            return Err(Error::new(interrupt::Interrupted).context(message));
+        }
+        let mut entry = entry.context("Failed to read tar entry")?;
+        let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        if let Some(only) = only
+            && !sanitize_path(&path).is_ok_and(|relative_path| only.contains(&relative_path))
+        {
+            continue;
+        }
+        let file = extract_entry(&mut entry, &path, destination, journal.as_deref_mut())
+            .with_context(|| format!("Failed to extract entry {path}"))?;
+        files.extend(file);
+        last_extracted = Some(path);
+    }
+
+    // Names of the extracted files are only written to the device along with their parent directory
+    let directories: BTreeSet<PathBuf> = files
+        .iter()
+        .flat_map(|file| file.path.ancestors().skip(1))
+        .map(|directory| destination.join(directory))
+        .collect();
+    for directory in directories {
+        disk::sync_directory(&directory)?;
+    }
+    Ok(files)
+}
+
+fn extract_entry<R: Read>(
+    entry: &mut Entry<R>,
+    path: &str,
+    destination: &Path,
+    journal: Option<&mut ExtractionJournal>,
+) -> Result<Option<ArchiveFile>, Error> {
+    let Some(relative_path) = entry_relative_path(entry, path)? else {
+        return Ok(None);
+    };
+    let destination_path = destination.join(&relative_path);
+    match entry.header().entry_type() {
+        EntryType::Directory => {
+            debug!("Creating directory {}", destination_path.to_string_lossy());
+            fs::create_dir_all(&destination_path).with_context(|| {
+                format!(
+                    "Failed to create directory {}",
+                    destination_path.to_string_lossy()
+                )
+            })?;
+        }
+        EntryType::Regular | EntryType::Continuous => {
+            let size = entry.size();
+            // Streamed archives are not validated beforehand, refusing the file before writing 4 GiB of it
+            if let Some(message) = fat32::check_file_size(size) {
+                return Err(anyhow!(message));
+            }
+            if let Some(journal) = &journal
+                && let Some(sha256) = journal.extracted_sha256(path, size, &destination_path)
+            {
+                // Content is skipped when reading the next entry
+                debug!(
+                    "Skipping file {}, already extracted",
+                    destination_path.to_string_lossy()
+                );
+                return Ok(Some(ArchiveFile {
+                    path: relative_path,
+                    size,
+                    sha256,
+                }));
+            }
+            if let Some(parent) = destination_path.parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("Failed to create directory {}", parent.to_string_lossy())
+                })?;
+            }
+            debug!("Extracting file {}", destination_path.to_string_lossy());
+            let file = File::create(&destination_path).with_context(|| {
+                format!(
+                    "Failed to create file {}",
+                    destination_path.to_string_lossy()
+                )
+            })?;
+            let mut writer = BufWriter::with_capacity(1024 * 1024, file);
+            let sha256 = copy_entry(entry, &mut writer, &destination_path.to_string_lossy())?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            // Keeping modification time as tar::Archive::unpack does. Permissions are not supported by FAT32
+            if let Ok(mtime) = entry.header().mtime()
+                && let Err(e) = file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+            {
+                debug!("Failed to set modification time: {e}");
+            }
+            // Written to the device right away, rather than being held in memory after extraction completed. Also
+            // needed to only record files actually written to the device in the journal.
+            file.sync_all().with_context(|| {
+                format!(
+                    "Failed to write file {}",
+                    destination_path.to_string_lossy()
+                )
+            })?;
+            if let Some(journal) = journal {
+                journal.record(path, size, &sha256)?;
+            }
+            return Ok(Some(ArchiveFile {
+                path: relative_path,
+                size,
+                sha256,
+            }));
+        }
+        entry_type => return Err(unsupported_entry_type(entry_type)),
+    }
+    Ok(None)
+}
+
+// Path of an entry relative to the destination, None for entries that do not describe anything to extract
+fn entry_relative_path<R: Read>(entry: &Entry<R>, path: &str) -> Result<Option<PathBuf>, Error> {
+    let entry_type = entry.header().entry_type();
+    if entry_type == EntryType::XGlobalHeader {
+        // Global pax extensions do not describe any file
+        return Ok(None);
+    }
+
+    let relative_path = sanitize_path(path)?;
+    if relative_path.as_os_str().is_empty() {
+        // Destination itself, e.g. "./" entry created by `tar -cf archive.tar .`
+        if entry_type == EntryType::Directory {
+            return Ok(None);
+        }
+        return Err(anyhow!("Empty path"));
+    }
+    Ok(Some(relative_path))
+}
+
+fn unsupported_entry_type(entry_type: EntryType) -> Error {
+    anyhow!("Unsupported entry type {entry_type:?}, only regular files and directories are allowed")
+}
+
+// Copy the content of a file entry, returning its SHA-256
+fn copy_entry<R: Read, W: Write>(
+    entry: &mut Entry<R>,
+    writer: &mut W,
+    destination_name: &str,
+) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = entry.read(&mut buffer).context("Failed to read archive")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        writer
+            .write_all(&buffer[..read])
+            .with_context(|| format!("Failed to write file {destination_name}"))?;
+    }
+    Ok(checksum::to_hex(&hasher.finalize()))
+}
+
+// Extract a tar archive to the root directory of a FAT32 file system, e.g. of a disk image file, with the same
+// restrictions as extract. Modification times are not kept, files being dated from the extraction.
+// Returns the files of the archive.
+pub fn extract_to_fat<R: Read, T: ReadWriteSeek>(
+    reader: R,
+    root: &Dir<T>,
+) -> Result<Vec<ArchiveFile>, Error> {
+    let mut files = Vec::new();
+    let mut ar = Archive::new(reader);
+    for entry in ar.entries().context("Failed to read tar entries")? {
+        if interrupt::is_interrupted() {
+            return Err(Error::new(interrupt::Interrupted));
+        }
+        let mut entry = entry.context("Failed to read tar entry")?;
+        let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        let file = extract_entry_to_fat(&mut entry, &path, root)
+            .with_context(|| format!("Failed to extract entry {path}"))?;
+        files.extend(file);
+    }
+    Ok(files)
+}
+
+fn extract_entry_to_fat<R: Read, T: ReadWriteSeek>(
+    entry: &mut Entry<R>,
+    path: &str,
+    root: &Dir<T>,
+) -> Result<Option<ArchiveFile>, Error> {
+    let Some(relative_path) = entry_relative_path(entry, path)? else {
+        return Ok(None);
+    };
+    match entry.header().entry_type() {
+        EntryType::Directory => create_fat_dir_all(root, &relative_path)?,
+        EntryType::Regular | EntryType::Continuous => {
+            let size = entry.size();
+            if let Some(message) = fat32::check_file_size(size) {
+                return Err(anyhow!(message));
+            }
+            if let Some(parent) = relative_path.parent() {
+                create_fat_dir_all(root, parent)?;
+            }
+            let fat_path = fat_path(&relative_path);
+            debug!("Extracting file {fat_path} to FAT32 file system");
+            let mut file = root
+                .create_file(&fat_path)
+                .and_then(|mut file| file.truncate().map(|_| file))
+                .with_context(|| format!("Failed to create file {fat_path}"))?;
+            let sha256 = copy_entry(entry, &mut file, &fat_path)?;
+            file.flush()
+                .with_context(|| format!("Failed to write file {fat_path}"))?;
+            return Ok(Some(ArchiveFile {
+                path: relative_path,
+                size,
+                sha256,
+            }));
+        }
+        entry_type => return Err(unsupported_entry_type(entry_type)),
+    }
+    Ok(None)
+}
+
+// Create a directory and its missing parents in a FAT32 file system
+fn create_fat_dir_all<T: ReadWriteSeek>(root: &Dir<T>, path: &Path) -> Result<(), Error> {
+    let mut directory = PathBuf::new();
+    for component in path.components() {
+        directory.push(component);
+        let fat_path = fat_path(&directory);
+        root.create_dir(&fat_path)
+            .with_context(|| format!("Failed to create directory {fat_path}"))?;
+    }
+    Ok(())
+}
+
+// Path in a FAT32 file system, whose names are separated by '/' whatever the system
+fn fat_path(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Convert an entry path to a path relative to the destination, refusing paths outside of the destination and
+// names that cannot be stored on FAT32
+fn sanitize_path(path: &str) -> Result<PathBuf, Error> {
+    if let Some(message) = check_path(path) {
+        return Err(anyhow!(message));
+    }
+    let mut relative_path = PathBuf::new();
+    for component in Path::new(path).components() {
+        if let Component::Normal(name) = component {
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("Name is not valid UTF-8"))?;
+            if let Some(message) = fat32::check_name(name) {
+                return Err(anyhow!(message));
+            }
+            relative_path.push(name);
+        }
+    }
+    Ok(relative_path)
+}
+
+// Compute the size and SHA-256 of the files of a tar archive, to verify files extracted from it
+pub fn hash_files(filename: &str) -> Result<Vec<ArchiveFile>, Error> {
+    let tar_file =
+        File::open(filename).with_context(|| format!("Failed to open archive {filename}"))?;
+    let mut files = Vec::new();
+    let mut ar = Archive::new(BufReader::with_capacity(1024 * 1024, tar_file));
+    for entry in ar.entries().context("Failed to read tar entries")? {
+        let mut entry = entry.context("Failed to read tar entry")?;
+        if !matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        ) {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        let relative_path =
+            sanitize_path(&path).with_context(|| format!("Failed to extract entry {path}"))?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut entry, &mut hasher)
+            .with_context(|| format!("Failed to read entry {path}"))?;
+        files.push(ArchiveFile {
+            path: relative_path,
+            size: entry.size(),
+            sha256: checksum::to_hex(&hasher.finalize()),
+        });
+    }
+    Ok(files)
+}
+
+// Read back the files extracted to destination, and compare their size and SHA-256 with the archive ones
+pub fn verify_extraction(
+    destination: &Path,
+    files: &[ArchiveFile],
+    progress_bar: &ProgressBar,
+) -> Result<Vec<Mismatch>, Error> {
+    progress_bar.set_length(files.iter().map(|file| file.size).sum());
+    let mut mismatches = Vec::new();
+    for file in files {
+        if interrupt::is_interrupted() {
+            return Err(Error::new(interrupt::Interrupted).context("Verification stopped"));
+        }
+        let destination_path = destination.join(&file.path);
+        if let Some(message) = verify_file(&destination_path, file, progress_bar) {
+            debug!(
+                "File {} does not match: {message}",
+                destination_path.to_string_lossy()
+            );
+            mismatches.push(Mismatch {
+                path: file.path.clone(),
+                message,
+            });
+        }
+    }
+    progress_bar.finish();
+    Ok(mismatches)
+}
+
+// Compare an extracted file with the archive one. Returns how it differs, if any.
+fn verify_file(
+    destination_path: &Path,
+    file: &ArchiveFile,
+    progress_bar: &ProgressBar,
+) -> Option<String> {
+    let destination_file = match File::open(destination_path) {
+        Ok(destination_file) => destination_file,
+        Err(e) => {
+            progress_bar.inc(file.size);
+            return Some(format!("Failed to open file: {e}"));
+        }
+    };
+    let size = destination_file
+        .metadata()
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    if size != file.size {
+        progress_bar.inc(file.size);
+        return Some(format!("Size is {size} bytes instead of {}", file.size));
+    }
+    // Reading from the USB drive rather than data still in memory
+    disk::drop_cache(&destination_file);
+    let mut reader =
+        progress_bar.wrap_read(BufReader::with_capacity(1024 * 1024, destination_file));
+    let mut hasher = Sha256::new();
+    if let Err(e) = io::copy(&mut reader, &mut hasher) {
+        return Some(format!("Failed to read file: {e}"));
+    }
+    let sha256 = checksum::to_hex(&hasher.finalize());
+    if sha256 != file.sha256 {
+        return Some(format!(
+            "Content differs from the update (SHA-256 {sha256} instead of {})",
+            file.sha256
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::{self, Cursor, Read, Write};
+
+    use tar::{Builder, Header};
+
+    // Header of an entry with the given path, written as is, unlike Header::set_path that refuses unsafe paths
+    fn header(path: &str, entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        header
+    }
+
+    fn file_header(path: &str, size: u64) -> Header {
+        header(path, EntryType::Regular, size)
+    }
+
+    fn link_header(path: &str, entry_type: EntryType, target: &str) -> Header {
+        let mut header = header(path, entry_type, 0);
+        header.set_link_name(target).unwrap();
+        header.set_cksum();
+        header
+    }
+
+    // Archive made of the given entries, all files being filled with zeros
+    fn archive(entries: &[Header]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for header in entries {
+            builder
+                .append(header, io::repeat(0).take(header.size().unwrap()))
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn validate_bytes(content: &[u8]) -> ArchiveReport {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), content).unwrap();
+        validate(&file.path().to_string_lossy()).unwrap()
+    }
+
+    fn validate_archive(entries: &[Header]) -> ArchiveReport {
+        validate_bytes(&archive(entries))
+    }
+
+    fn issue_messages(report: &ArchiveReport) -> Vec<(&str, &str)> {
+        report
+            .issues
+            .iter()
+            .map(|issue| (issue.entry.as_str(), issue.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_accepts_valid_archive() {
+        let report = validate_archive(&[
+            header("./", EntryType::Directory, 0),
+            header("./dir/", EntryType::Directory, 0),
+            file_header("./dir/file.txt", 1000),
+            file_header("license.txt", 10),
+        ]);
+        assert_eq!(issue_messages(&report), vec![]);
+        assert_eq!(report.entry_count, 4);
+        assert_eq!(report.total_size, 1010);
+    }
+
+    #[test]
+    fn validate_reports_truncated_archive() {
+        let content = archive(&[file_header("file.txt", 2000)]);
+        // Cut in the middle of the content of the file
+        let report = validate_bytes(&content[..1024]);
+        assert_eq!(
+            issue_messages(&report),
+            vec![(
+                "file.txt",
+                "Content goes beyond the end of the archive, archive is truncated"
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_reports_missing_end_of_archive_marker() {
+        let content = archive(&[file_header("file.txt", 10)]);
+        // Without the two empty blocks ending the archive
+        let report = validate_bytes(&content[..1024]);
+        assert_eq!(
+            issue_messages(&report),
+            vec![(
+                "end of archive at offset 1024",
+                "No end-of-archive marker, archive is probably truncated"
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_refuses_paths_outside_of_destination() {
+        let report = validate_archive(&[
+            file_header("../file.txt", 1),
+            file_header("dir/../../file.txt", 1),
+            file_header("/etc/passwd", 1),
+        ]);
+        assert_eq!(
+            issue_messages(&report),
+            vec![
+                ("../file.txt", "Path goes outside of the destination (..)"),
+                (
+                    "dir/../../file.txt",
+                    "Path goes outside of the destination (..)"
+                ),
+                ("/etc/passwd", "Absolute path"),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_refuses_links() {
+        let report = validate_archive(&[
+            file_header("file.txt", 1),
+            link_header("symlink", EntryType::Symlink, "/etc/passwd"),
+            link_header("hardlink", EntryType::Link, "file.txt"),
+        ]);
+        let entries: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| issue.entry.as_str())
+            .collect();
+        assert_eq!(entries, vec!["symlink", "hardlink"]);
+        assert!(
+            report.issues[0]
+                .message
+                .starts_with("Unsupported entry type Symlink")
+        );
+        assert!(
+            report.issues[1]
+                .message
+                .starts_with("Unsupported entry type Link")
+        );
+    }
+
+    #[test]
+    fn validate_refuses_names_not_allowed_on_fat32() {
+        let report = validate_archive(&[
+            file_header("a:b.txt", 1),
+            file_header("CON", 1),
+            file_header("dir/con.txt", 1),
+            file_header("name.", 1),
+        ]);
+        let entries: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| issue.entry.as_str())
+            .collect();
+        assert_eq!(entries, vec!["a:b.txt", "CON", "dir/con.txt", "name."]);
+    }
+
+    #[test]
+    fn validate_refuses_long_names_and_paths() {
+        let mut builder = Builder::new(Vec::new());
+        let long_name = "a".repeat(256);
+        let long_path = format!("{}/{}/file.txt", "a".repeat(124), "b".repeat(124));
+        for path in ["a".repeat(255), long_name.clone(), long_path.clone()] {
+            let mut header = Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            // Written as GNU long names, not fitting in the header
+            builder
+                .append_data(&mut header, &path, io::empty())
+                .unwrap();
+        }
+        let report = validate_bytes(&builder.into_inner().unwrap());
+
+        let entries: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| issue.entry.clone())
+            .collect();
+        assert_eq!(entries, vec![long_name, long_path]);
+        assert!(
+            report.issues[0]
+                .message
+                .starts_with("Name is longer than 255 characters")
+        );
+        assert!(
+            report.issues[1]
+                .message
+                .starts_with("Path is 258 characters long")
+        );
+    }
+
+    #[test]
+    fn validate_refuses_files_over_4_gib() {
+        // Sparse archive, contents being skipped by validate
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let size = fat32::MAX_FILE_SIZE + 1;
+        file.write_all(file_header("large.bin", size).as_bytes())
+            .unwrap();
+        let end = BLOCK_SIZE + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        file.as_file().set_len(end + 2 * BLOCK_SIZE).unwrap();
+        let report = validate(&file.path().to_string_lossy()).unwrap();
+
+        assert_eq!(report.total_size, size);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].entry, "large.bin");
+        assert!(
+            report.issues[0]
+                .message
+                .contains("larger than the 4294967295 bytes")
+        );
+    }
+
+    #[test]
+    fn validate_refuses_names_differing_only_by_case() {
+        let report = validate_archive(&[
+            header("Dir/", EntryType::Directory, 0),
+            file_header("Dir/File.txt", 1),
+            file_header("./dir/FILE.TXT", 1),
+            header("./Dir", EntryType::Directory, 0),
+        ]);
+        assert_eq!(
+            issue_messages(&report),
+            vec![(
+                "./dir/FILE.TXT",
+                "Same name as Dir/File.txt on FAT32, names being case-insensitive"
+            )]
+        );
+    }
+
+    #[test]
+    fn sanitize_path_returns_relative_path() {
+        assert_eq!(
+            sanitize_path("./dir/file.txt").unwrap(),
+            PathBuf::from("dir/file.txt")
+        );
+        assert_eq!(sanitize_path("dir/").unwrap(), PathBuf::from("dir"));
+        assert_eq!(sanitize_path("./").unwrap(), PathBuf::new());
+    }
+
+    #[test]
+    fn sanitize_path_refuses_unsafe_paths() {
+        assert!(sanitize_path("../file.txt").is_err());
+        assert!(sanitize_path("dir/../../file.txt").is_err());
+        assert!(sanitize_path("dir/../file.txt").is_err());
+        assert!(sanitize_path("/etc/passwd").is_err());
+        assert!(sanitize_path("dir/con.txt").is_err());
+        assert!(sanitize_path("dir/a|b").is_err());
+    }
+
+    #[test]
+    fn extract_writes_files_below_destination() {
+        let destination = tempfile::tempdir().unwrap();
+        let files = extract(
+            Cursor::new(archive(&[
+                header("./dir/", EntryType::Directory, 0),
+                file_header("./dir/file.txt", 3),
+            ])),
+            destination.path(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("dir/file.txt"));
+        assert_eq!(
+            fs::read(destination.path().join("dir/file.txt")).unwrap(),
+            vec![0; 3]
+        );
+    }
+
+    #[test]
+    fn extract_refuses_unsafe_entries() {
+        let parent = tempfile::tempdir().unwrap();
+        let destination = parent.path().join("destination");
+        fs::create_dir(&destination).unwrap();
+        for entry in [
+            file_header("../outside.txt", 1),
+            file_header("/tmp/absolute.txt", 1),
+            link_header("symlink", EntryType::Symlink, "../outside.txt"),
+            link_header("hardlink", EntryType::Link, "../outside.txt"),
+            file_header("con.txt", 1),
+        ] {
+            let path = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+            let result = extract(Cursor::new(archive(&[entry])), &destination, None);
+            assert!(result.is_err(), "{path}");
+        }
+        assert_eq!(fs::read_dir(&destination).unwrap().count(), 0);
+        assert!(!parent.path().join("outside.txt").exists());
+    }
+}
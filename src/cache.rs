@@ -0,0 +1,444 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+
+use serde::{Deserialize, Serialize};
+
+use log::debug;
+
+use console::Style;
+
+use indicatif::DecimalBytes;
+
+use crate::psa::SoftwareUpdate;
+
+// Name of the directory of the application in the user cache directory
+const CACHE_DIRECTORY_NAME: &str = "psa-update";
+
+// Description of the update, in each update directory
+const METADATA_FILENAME: &str = "update.json";
+
+// Default directory where updates are downloaded: psa-update directory in the user cache directory ($XDG_CACHE_HOME
+// or ~/.cache on Linux, ~/Library/Caches on macOS, %LOCALAPPDATA% on Windows), so that updates are kept whatever the
+// current directory. Falls back to the current directory when there is no cache directory.
+pub fn default_download_dir() -> PathBuf {
+    match dirs::cache_dir() {
+        Some(cache_dir) => cache_dir.join(CACHE_DIRECTORY_NAME),
+        None => {
+            debug!("No user cache directory, downloading to current directory");
+            PathBuf::from(".")
+        }
+    }
+}
+
+// Directory of an update in the download directory. Named after the update id and version only, so that an update
+// downloaded for a VIN is reused for any other VIN requiring the same update.
+pub fn update_dir(download_dir: &Path, update: &SoftwareUpdate) -> PathBuf {
+    let name: String = format!("{}_{}", update.update_id, update.update_version)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    download_dir.join(name)
+}
+
+// Description of a downloaded update, stored in its directory so that the cache can be listed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateMetadata {
+    #[serde(rename = "softwareType")]
+    pub software_type: String,
+    pub id: String,
+    pub version: String,
+    pub date: String,
+    pub size: Option<u64>,
+    // File names in the update directory, set once downloaded
+    #[serde(rename = "updateFilename")]
+    pub update_filename: Option<String>,
+    #[serde(rename = "licenseFilename")]
+    pub license_filename: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheStatus {
+    Complete,
+    // Download started, not completed
+    Partial,
+    // Update file not downloaded or removed, only the license remains
+    Missing,
+}
+
+#[derive(Debug)]
+pub struct CacheEntry {
+    pub directory: PathBuf,
+    // None for directories not created by psa-update
+    pub metadata: Option<UpdateMetadata>,
+    pub status: CacheStatus,
+    // Space used by the files of the directory
+    pub disk_size: u64,
+}
+
+impl UpdateMetadata {
+    pub fn new(update: &SoftwareUpdate) -> UpdateMetadata {
+        UpdateMetadata {
+            software_type: update.software_type.clone(),
+            id: update.update_id.clone(),
+            version: update.update_version.clone(),
+            date: update.update_date.clone(),
+            size: update.update_size.parse().ok(),
+            update_filename: None,
+            license_filename: None,
+        }
+    }
+}
+
+// Record the description of an update in its directory. Files are given by their path.
+pub fn write_metadata(
+    directory: &Path,
+    update: &SoftwareUpdate,
+    license_filename: Option<&str>,
+    update_filename: Option<&str>,
+) -> Result<(), Error> {
+    let file_name = |filename: &str| {
+        Path::new(filename)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+    };
+    let metadata = UpdateMetadata {
+        license_filename: license_filename.and_then(file_name),
+        update_filename: update_filename.and_then(file_name),
+        ..UpdateMetadata::new(update)
+    };
+    let metadata_filename = directory.join(METADATA_FILENAME);
+    debug!(
+        "Writing {metadata:?} to {}",
+        metadata_filename.to_string_lossy()
+    );
+    let content =
+        serde_json::to_string_pretty(&metadata).context("Failed to serialize update metadata")?;
+    fs::write(&metadata_filename, content).with_context(|| {
+        format!(
+            "Failed to write update metadata {}",
+            metadata_filename.to_string_lossy()
+        )
+    })
+}
+
+fn read_metadata(directory: &Path) -> Option<UpdateMetadata> {
+    let metadata_filename = directory.join(METADATA_FILENAME);
+    let content = fs::read_to_string(&metadata_filename).ok()?;
+    match serde_json::from_str(&content) {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            debug!(
+                "Ignoring invalid update metadata {}: {e}",
+                metadata_filename.to_string_lossy()
+            );
+            None
+        }
+    }
+}
+
+// List updates in the download directory, sorted by software type and date
+pub fn list(download_dir: &Path) -> Result<Vec<CacheEntry>, Error> {
+    let mut entries = Vec::new();
+    let dir_entries = match fs::read_dir(download_dir) {
+        Ok(dir_entries) => dir_entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(entries),
+        Err(e) => {
+            return Err(Error::new(e).context(format!(
+                "Failed to list download directory {}",
+                download_dir.to_string_lossy()
+            )));
+        }
+    };
+    for dir_entry in dir_entries {
+        let directory = dir_entry
+            .context("Failed to list download directory")?
+            .path();
+        if !directory.is_dir() {
+            continue;
+        }
+        entries.push(read_entry(directory)?);
+    }
+    entries.sort_by(|a, b| {
+        let key = |entry: &CacheEntry| {
+            entry
+                .metadata
+                .as_ref()
+                .map(|m| (m.software_type.clone(), m.date.clone()))
+        };
+        key(a).cmp(&key(b))
+    });
+    Ok(entries)
+}
+
+fn read_entry(directory: PathBuf) -> Result<CacheEntry, Error> {
+    let metadata = read_metadata(&directory);
+    let mut disk_size = 0;
+    let mut has_part = false;
+    for file in fs::read_dir(&directory)
+        .with_context(|| format!("Failed to list files in {}", directory.to_string_lossy()))?
+    {
+        let file = file.context("Failed to list files")?;
+        disk_size += file.metadata().map(|m| m.len()).unwrap_or(0);
+        has_part |= file.file_name().to_string_lossy().ends_with(".part");
+    }
+    let complete = metadata
+        .as_ref()
+        .and_then(|m| m.update_filename.as_ref())
+        .is_some_and(|name| directory.join(name).is_file());
+    let status = if complete {
+        CacheStatus::Complete
+    } else if has_part {
+        CacheStatus::Partial
+    } else {
+        CacheStatus::Missing
+    };
+    Ok(CacheEntry {
+        directory,
+        metadata,
+        status,
+        disk_size,
+    })
+}
+
+// Print cached updates as a table
+pub fn print(entries: &[CacheEntry]) {
+    println!(
+        "{0: <28} | {1: <28} | {2: <21} | {3: >10} | {4: <8} | Directory",
+        "Software type", "Version", "Date", "Size", "Status"
+    );
+    println!("{}", "-".repeat(130));
+    let red = Style::new().red();
+    let green = Style::new().green();
+    let yellow = Style::new().yellow();
+    for entry in entries {
+        let status_styled = match entry.status {
+            CacheStatus::Complete => green.apply_to("complete"),
+            CacheStatus::Partial => yellow.apply_to("partial"),
+            CacheStatus::Missing => red.apply_to("missing"),
+        };
+        let (software_type, version, date) = match &entry.metadata {
+            Some(metadata) => (
+                metadata.software_type.as_str(),
+                metadata.version.as_str(),
+                metadata.date.as_str(),
+            ),
+            None => ("unknown", "unknown", ""),
+        };
+        println!(
+            "{0: <28} | {1: <28} | {2: <21} | {3: >10} | {4: <8} | {5}",
+            software_type,
+            version,
+            date,
+            DecimalBytes(entry.disk_size).to_string(),
+            status_styled,
+            entry.directory.to_string_lossy()
+        );
+    }
+}
+
+// Updates superseded by a more recent update of the same software type, that was completely downloaded.
+// Updates without a valid date are never considered obsolete.
+pub fn obsolete_entries(entries: &[CacheEntry]) -> Vec<&CacheEntry> {
+    entries
+        .iter()
+        .filter(|entry| {
+            let Some(metadata) = &entry.metadata else {
+                return false;
+            };
+            let Some(date) = parse_date(&metadata.date) else {
+                return false;
+            };
+            entries.iter().any(|other| {
+                other.status == CacheStatus::Complete
+                    && other.metadata.as_ref().is_some_and(|other| {
+                        other.software_type == metadata.software_type
+                            && parse_date(&other.date).is_some_and(|other_date| other_date > date)
+                    })
+            })
+        })
+        .collect()
+}
+
+// Parse the date of an update as sent by the server, e.g. "2021-02-07 11:47:22.0", into comparable numbers from
+// the year down to the fraction of second. Time is optional.
+fn parse_date(date: &str) -> Option<[u32; 7]> {
+    let mut fields = [0; 7];
+    let mut count = 0;
+    for field in date.trim().split(['-', ' ', ':', '.']) {
+        *fields.get_mut(count)? = field.parse().ok()?;
+        count += 1;
+    }
+    (count >= 3).then_some(fields)
+}
+
+// Updates whose update file is missing: only the license written along with the update remains
+pub fn orphan_entries(entries: &[CacheEntry]) -> Vec<&CacheEntry> {
+    entries
+        .iter()
+        .filter(|entry| entry.metadata.is_some() && entry.status == CacheStatus::Missing)
+        .collect()
+}
+
+pub fn remove(entry: &CacheEntry) -> Result<(), Error> {
+    debug!("Removing {}", entry.directory.to_string_lossy());
+    fs::remove_dir_all(&entry.directory).with_context(|| {
+        format!(
+            "Failed to remove directory {}",
+            entry.directory.to_string_lossy()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, software_type: &str, date: &str, status: CacheStatus) -> CacheEntry {
+        CacheEntry {
+            directory: PathBuf::from(name),
+            metadata: Some(UpdateMetadata {
+                software_type: software_type.to_string(),
+                id: name.to_string(),
+                version: "1.0".to_string(),
+                date: date.to_string(),
+                size: None,
+                update_filename: None,
+                license_filename: None,
+            }),
+            status,
+            disk_size: 0,
+        }
+    }
+
+    fn names(entries: Vec<&CacheEntry>) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.directory.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn dates_are_compared_as_dates() {
+        assert_eq!(
+            parse_date("2021-02-07 11:47:22.0"),
+            Some([2021, 2, 7, 11, 47, 22, 0])
+        );
+        assert_eq!(parse_date("2021-02-07"), Some([2021, 2, 7, 0, 0, 0, 0]));
+        assert!(parse_date("2021-2-7 9:05:00.0") < parse_date("2021-02-07 11:47:22.0"));
+        assert!(parse_date("2021-12-01 00:00:00.0") > parse_date("2021-9-30 23:59:59.0"));
+        assert_eq!(parse_date(""), None);
+        assert_eq!(parse_date("2021-02"), None);
+        assert_eq!(parse_date("07/02/2021"), None);
+        assert_eq!(parse_date("2021-02-07 11:47:22.0.1"), None);
+    }
+
+    #[test]
+    fn older_versions_of_a_complete_update_are_obsolete() {
+        let entries = [
+            entry(
+                "map-2020",
+                "map",
+                "2020-06-15 10:00:00.0",
+                CacheStatus::Complete,
+            ),
+            entry(
+                "map-2021",
+                "map",
+                "2021-02-07 11:47:22.0",
+                CacheStatus::Complete,
+            ),
+            entry(
+                "map-2021-9",
+                "map",
+                "2021-9-01 08:00:00.0",
+                CacheStatus::Partial,
+            ),
+            entry(
+                "fw-2021-9",
+                "fw",
+                "2021-9-30 00:00:00.0",
+                CacheStatus::Complete,
+            ),
+            entry(
+                "fw-2021-10",
+                "fw",
+                "2021-10-01 00:00:00.0",
+                CacheStatus::Complete,
+            ),
+        ];
+        // Newest map update is only partially downloaded, it does not supersede the complete one
+        assert_eq!(
+            names(obsolete_entries(&entries)),
+            vec!["map-2020", "fw-2021-9"]
+        );
+    }
+
+    #[test]
+    fn entries_without_date_are_not_obsolete() {
+        let entries = [
+            entry("no-date", "map", "", CacheStatus::Complete),
+            entry("invalid-date", "map", "unknown", CacheStatus::Complete),
+            entry(
+                "map-2021",
+                "map",
+                "2021-02-07 11:47:22.0",
+                CacheStatus::Complete,
+            ),
+        ];
+        assert!(obsolete_entries(&entries).is_empty());
+    }
+
+    #[test]
+    fn entries_not_created_by_psa_update_are_kept() {
+        let entries = [
+            CacheEntry {
+                directory: PathBuf::from("other"),
+                metadata: None,
+                status: CacheStatus::Missing,
+                disk_size: 0,
+            },
+            entry(
+                "map-2021",
+                "map",
+                "2021-02-07 11:47:22.0",
+                CacheStatus::Complete,
+            ),
+        ];
+        assert!(obsolete_entries(&entries).is_empty());
+        assert!(orphan_entries(&entries).is_empty());
+    }
+
+    #[test]
+    fn license_without_update_is_an_orphan() {
+        let entries = [
+            entry(
+                "license-only",
+                "map",
+                "2020-06-15 10:00:00.0",
+                CacheStatus::Missing,
+            ),
+            entry(
+                "partial",
+                "fw",
+                "2021-02-07 11:47:22.0",
+                CacheStatus::Partial,
+            ),
+            entry(
+                "complete",
+                "fw",
+                "2021-04-19 17:38:57.0",
+                CacheStatus::Complete,
+            ),
+        ];
+        assert_eq!(names(orphan_entries(&entries)), vec!["license-only"]);
+    }
+}
@@ -0,0 +1,386 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use anyhow::{Context, Error, Result, anyhow};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use log::debug;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use indicatif::{MultiProgress, ProgressBar};
+
+use reqwest::header::{ETAG, HeaderMap};
+
+use crate::interact;
+use crate::interrupt;
+
+// Extension of the local manifest recording the SHA-256 of a downloaded file, next to the file itself.
+// Same format as the sha256sum tool, so that it can also be checked with `sha256sum -c`
+const MANIFEST_EXTENSION: &str = "sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+// Digest of a file as advertised by the server in a response header
+#[derive(Debug, Clone)]
+pub struct ServerDigest {
+    pub algorithm: Algorithm,
+    pub value: Vec<u8>,
+    pub header: &'static str,
+    // ETag is only a digest by convention (e.g. AWS S3 simple uploads): a mismatch is not considered as an error
+    pub strict: bool,
+}
+
+pub struct FileHashes {
+    pub md5: Vec<u8>,
+    pub sha256: Vec<u8>,
+}
+
+impl FileHashes {
+    fn get(&self, algorithm: Algorithm) -> &[u8] {
+        match algorithm {
+            Algorithm::Md5 => &self.md5,
+            Algorithm::Sha256 => &self.sha256,
+        }
+    }
+}
+
+// Incremental computation of MD5 and SHA-256, for data not stored in a file
+#[derive(Default)]
+pub struct Hasher {
+    md5: Md5,
+    sha256: Sha256,
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.md5.update(data);
+        self.sha256.update(data);
+    }
+
+    pub fn finalize(self) -> FileHashes {
+        FileHashes {
+            md5: self.md5.finalize().to_vec(),
+            sha256: self.sha256.finalize().to_vec(),
+        }
+    }
+}
+
+// Parse digests of the file from the response headers, if any
+pub fn parse_server_digests(headers: &HeaderMap) -> Vec<ServerDigest> {
+    let mut digests = Vec::new();
+
+    // Content-MD5: base64 encoded MD5 (RFC 1864)
+    if let Some(value) = header_str(headers, "content-md5")
+        && let Ok(md5) = BASE64.decode(value)
+        && md5.len() == 16
+    {
+        digests.push(ServerDigest {
+            algorithm: Algorithm::Md5,
+            value: md5,
+            header: "content-md5",
+            strict: true,
+        });
+    }
+
+    // x-amz-checksum-sha256: base64 encoded SHA-256, sent by AWS S3 when the object was uploaded with a checksum
+    if let Some(value) = header_str(headers, "x-amz-checksum-sha256")
+        && let Ok(sha256) = BASE64.decode(value)
+        && sha256.len() == 32
+    {
+        digests.push(ServerDigest {
+            algorithm: Algorithm::Sha256,
+            value: sha256,
+            header: "x-amz-checksum-sha256",
+            strict: true,
+        });
+    }
+
+    // ETag: hex encoded MD5 for most static file servers and simple S3 uploads.
+    // Weak ETags and multipart S3 uploads ("<md5>-<parts count>") are not digests of the file
+    if let Some(value) = header_str(headers, ETAG.as_str())
+        && !value.starts_with("W/")
+        && let Some(md5) = from_hex(value.trim_matches('"'))
+        && md5.len() == 16
+    {
+        digests.push(ServerDigest {
+            algorithm: Algorithm::Md5,
+            value: md5,
+            header: "etag",
+            strict: false,
+        });
+    }
+
+    debug!("Digests found in response headers: {digests:?}");
+    digests
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// Compute MD5 and SHA-256 of a file in a single pass
+pub fn hash_file(filename: &str, progress_bar: &ProgressBar) -> Result<FileHashes, Error> {
+    let file = File::open(filename).with_context(|| format!("Failed to open file {filename}"))?;
+    let file_size = file
+        .metadata()
+        .with_context(|| format!("Failed to get metadata of file {filename}"))?
+        .len();
+    progress_bar.set_length(file_size);
+
+    let mut reader = BufReader::with_capacity(1024 * 1024, file);
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut hasher = Hasher::default();
+    loop {
+        // Hashing a large update takes a while
+        if interrupt::is_interrupted() {
+            progress_bar.abandon();
+            return Err(Error::new(interrupt::Interrupted));
+        }
+        let read = reader
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read file {filename}"))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        progress_bar.inc(read as u64);
+    }
+    progress_bar.finish();
+
+    Ok(hasher.finalize())
+}
+
+// Check a downloaded file against the digests provided by the server, and record its SHA-256 in a local manifest
+pub async fn record_download(
+    filename: &str,
+    digests: &[ServerDigest],
+    multi_progress: &MultiProgress,
+) -> Result<(), Error> {
+    let hashes = check_download(filename, digests, multi_progress).await?;
+    write_manifest(filename, &hashes.sha256)
+}
+
+// Hash a downloaded file and check it against the digests provided by the server
+pub async fn check_download(
+    filename: &str,
+    digests: &[ServerDigest],
+    multi_progress: &MultiProgress,
+) -> Result<FileHashes, Error> {
+    let progress_bar = multi_progress.add(interact::progress_bar(0));
+    progress_bar.set_message(format!("Computing checksum of {filename}"));
+
+    let filename_owned = filename.to_string();
+    let hashes = tokio::task::spawn_blocking(move || hash_file(&filename_owned, &progress_bar))
+        .await
+        .context("Failed to compute checksum")??;
+
+    check_server_digests(filename, &hashes, digests)?;
+    Ok(hashes)
+}
+
+// Check hashes of a downloaded file against the digests provided by the server
+pub fn check_server_digests(
+    filename: &str,
+    hashes: &FileHashes,
+    digests: &[ServerDigest],
+) -> Result<(), Error> {
+    for digest in digests {
+        let actual = hashes.get(digest.algorithm);
+        if actual == digest.value {
+            debug!("File {filename} matches {} digest", digest.header);
+        } else if digest.strict {
+            return Err(anyhow!(
+                "Downloaded file {filename} is corrupted: {} header is {} but file digest is {}. Please delete the file and download it again.",
+                digest.header,
+                to_hex(&digest.value),
+                to_hex(actual)
+            ));
+        } else {
+            debug!(
+                "File {filename} does not match {} header, which is probably not a digest",
+                digest.header
+            );
+        }
+    }
+    Ok(())
+}
+
+// Whether a local manifest was recorded for the file
+pub fn has_manifest(filename: &str) -> bool {
+    fs::metadata(manifest_filename(filename)).is_ok()
+}
+
+// Re-hash a file and compare it to its local manifest.
+// Returns false when there is no manifest to compare to.
+pub fn verify_manifest(filename: &str) -> Result<bool, Error> {
+    let expected = match read_manifest(filename)? {
+        Some(expected) => expected,
+        None => return Ok(false),
+    };
+
+    let progress_bar = interact::progress_bar(0);
+    progress_bar.set_message(format!("Verifying checksum of {filename}"));
+    let hashes = hash_file(filename, &progress_bar)?;
+    let actual = to_hex(&hashes.sha256);
+    if actual != expected {
+        return Err(anyhow!(
+            "File {filename} is corrupted: expected SHA-256 {expected} but got {actual}. Please delete the file and download it again."
+        ));
+    }
+    Ok(true)
+}
+
+fn manifest_filename(filename: &str) -> String {
+    format!("{filename}.{MANIFEST_EXTENSION}")
+}
+
+pub fn write_manifest(filename: &str, sha256: &[u8]) -> Result<(), Error> {
+    let manifest_filename = manifest_filename(filename);
+    // Only the file name is recorded, the manifest being stored in the same directory
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+    debug!("Writing manifest {manifest_filename}");
+    fs::write(
+        &manifest_filename,
+        format!("{}  {}\n", to_hex(sha256), name),
+    )
+    .with_context(|| format!("Failed to write manifest {manifest_filename}"))
+}
+
+fn read_manifest(filename: &str) -> Result<Option<String>, Error> {
+    let manifest_filename = manifest_filename(filename);
+    let content = match fs::read_to_string(&manifest_filename) {
+        Ok(content) => content,
+        Err(e) => {
+            debug!("No manifest {manifest_filename}: {e}");
+            return Ok(None);
+        }
+    };
+    match content.split_whitespace().next() {
+        Some(sha256) if from_hex(sha256).is_some_and(|s| s.len() == 32) => {
+            Ok(Some(sha256.to_ascii_lowercase()))
+        }
+        _ => Err(anyhow!("Invalid manifest {manifest_filename}")),
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use reqwest::header::HeaderValue;
+
+    // MD5 and SHA-256 of "hello"
+    const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn content_md5_is_decoded_from_base64() {
+        let md5 = from_hex(HELLO_MD5).unwrap();
+        let digests = parse_server_digests(&headers(&[("content-md5", &BASE64.encode(&md5))]));
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].algorithm, Algorithm::Md5);
+        assert_eq!(digests[0].value, md5);
+        assert_eq!(digests[0].header, "content-md5");
+        assert!(digests[0].strict);
+    }
+
+    #[test]
+    fn amz_checksum_sha256_is_decoded_from_base64() {
+        let sha256 = from_hex(HELLO_SHA256).unwrap();
+        let digests = parse_server_digests(&headers(&[(
+            "x-amz-checksum-sha256",
+            &BASE64.encode(&sha256),
+        )]));
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].algorithm, Algorithm::Sha256);
+        assert_eq!(digests[0].value, sha256);
+        assert!(digests[0].strict);
+    }
+
+    #[test]
+    fn invalid_base64_digests_are_ignored() {
+        let digests = parse_server_digests(&headers(&[
+            ("content-md5", "not base64!"),
+            // Valid base64, but not the size of a SHA-256
+            ("x-amz-checksum-sha256", &BASE64.encode([0; 16])),
+        ]));
+        assert!(digests.is_empty());
+    }
+
+    #[test]
+    fn etag_is_a_lenient_md5() {
+        let digests = parse_server_digests(&headers(&[("etag", &format!("\"{HELLO_MD5}\""))]));
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].algorithm, Algorithm::Md5);
+        assert_eq!(digests[0].value, from_hex(HELLO_MD5).unwrap());
+        assert_eq!(digests[0].header, "etag");
+        assert!(!digests[0].strict);
+    }
+
+    #[test]
+    fn multipart_and_weak_etags_are_not_digests() {
+        let multipart = format!("\"{HELLO_MD5}-3\"");
+        assert!(parse_server_digests(&headers(&[("etag", &multipart)])).is_empty());
+        let weak = format!("W/\"{HELLO_MD5}\"");
+        assert!(parse_server_digests(&headers(&[("etag", &weak)])).is_empty());
+        assert!(parse_server_digests(&headers(&[("etag", "\"5f3a-1b2c\"")])).is_empty());
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(from_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(to_hex(&[0x00, 0xff, 0x7a]), "00ff7a");
+        assert_eq!(from_hex(""), Some(vec![]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("é0"), None);
+    }
+
+    #[test]
+    fn lenient_digest_mismatch_is_not_an_error() {
+        let hashes = FileHashes {
+            md5: from_hex(HELLO_MD5).unwrap(),
+            sha256: from_hex(HELLO_SHA256).unwrap(),
+        };
+        let mut digest = ServerDigest {
+            algorithm: Algorithm::Md5,
+            value: vec![0; 16],
+            header: "etag",
+            strict: false,
+        };
+        assert!(check_server_digests("file", &hashes, &[digest.clone()]).is_ok());
+        digest.strict = true;
+        assert!(check_server_digests("file", &hashes, &[digest]).is_err());
+    }
+}
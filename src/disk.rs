@@ -1,5 +1,11 @@
-use std::env::current_dir;
+use std::fmt;
 use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::process::Command;
+
+use anyhow::{Context, Error, Result, anyhow};
 
 use sysinfo::{Disk, Disks};
 
@@ -8,8 +14,97 @@ use indicatif::DecimalBytes;
 
 use log::debug;
 
+use crate::units::{Base, parse_bytes};
+
+// Disk as listed by print_disks, with what makes it suitable to extract updates to
+pub struct DiskInfo {
+    pub name: String,
+    pub mount_point: PathBuf,
+    pub file_system: String,
+    pub label: Option<String>,
+    pub removable: bool,
+    pub total_space: u64,
+    pub available_space: u64,
+    // None when files could not be listed
+    pub empty: Option<bool>,
+}
+
+impl DiskInfo {
+    fn new(disk: &Disk) -> DiskInfo {
+        // List files on disk to determine if it's empty
+        let empty = match list_files(disk.mount_point()) {
+            Ok(files) => Some(files.is_empty()),
+            Err(e) => {
+                debug!("Failed to list files: {e}");
+                None
+            }
+        };
+        DiskInfo {
+            name: disk.name().to_string_lossy().to_string(),
+            mount_point: disk.mount_point().to_path_buf(),
+            file_system: disk.file_system().to_string_lossy().to_string(),
+            label: disk_label(disk),
+            removable: disk.is_removable(),
+            total_space: disk.total_space(),
+            available_space: disk.available_space(),
+            empty,
+        }
+    }
+
+    fn is_fat32(&self) -> bool {
+        self.file_system.eq_ignore_ascii_case("vfat")
+            || self.file_system.eq_ignore_ascii_case("fat32")
+    }
+
+    // Issues making the disk unsuitable to extract updates to, its content aside
+    fn issues(&self, required_space: u64) -> Vec<String> {
+        let mut issues = Vec::new();
+        if self.available_space < required_space {
+            issues.push(format!(
+                "not enough space, {} available but {} required",
+                DecimalBytes(self.available_space),
+                DecimalBytes(required_space)
+            ));
+        }
+        if !self.is_fat32() {
+            issues.push(format!(
+                "file system is {} instead of FAT32",
+                self.file_system
+            ));
+        }
+        if !self.removable {
+            issues.push(format!("disk {} is not removable", self.name));
+        }
+        issues
+    }
+
+    // Issues making the disk unsuitable to extract updates to, including its content. Suitable when there is none.
+    pub fn suitability_issues(&self, required_space: u64) -> Vec<String> {
+        let mut issues = self.issues(required_space);
+        match self.empty {
+            Some(true) => {}
+            Some(false) => issues.push("not empty".to_string()),
+            None => issues.push("failed to list files".to_string()),
+        }
+        issues
+    }
+}
+
+// Disks of the system, as listed by print_disks
+pub fn list_disks() -> Vec<DiskInfo> {
+    Disks::new_with_refreshed_list()
+        .iter()
+        .map(DiskInfo::new)
+        .collect()
+}
+
 // Print disks list as a table
 pub fn print_disks(required_space: u64) {
+    print_disk_table(&list_disks(), required_space);
+}
+
+// Print the given disks as a table, highlighting the disks with less than required_space available
+pub fn print_disk_table(disks: &[DiskInfo], required_space: u64) {
     println!(
         "{0: <20} | {1: <35} | {2: <6} | {3: <5} | {4: >10} | {5: <5}",
         "Name", "Path", "Type", "USB", "Space", "Empty"
@@ -17,58 +112,34 @@ pub fn print_disks(required_space: u64) {
     println!("{}", "-".repeat(95));
     let red = Style::new().red();
     let green = Style::new().green();
-    let disks = Disks::new_with_refreshed_list();
-    for disk in &disks {
-        let disk_removable_styled = if disk.is_removable() {
+    for disk in disks {
+        let disk_removable_styled = if disk.removable {
             green.apply_to("Yes")
         } else {
             red.apply_to("No")
         };
-        let file_system_str = disk.file_system().to_string_lossy();
-        let file_system_styled = if file_system_str.eq_ignore_ascii_case("vfat")
-            || file_system_str.eq_ignore_ascii_case("fat32")
-        {
-            green.apply_to(file_system_str)
+        let file_system_styled = if disk.is_fat32() {
+            green.apply_to(disk.file_system.as_str())
         } else {
-            red.apply_to(file_system_str)
+            red.apply_to(disk.file_system.as_str())
         };
 
-        // List files on disk to determine if it's empty
-        let disk_files = fs::read_dir(disk.mount_point());
-        let empty_styled = match disk_files {
-            Ok(files) => {
-                // Ignore System Volume Information folder presence on Windows as it is created by the OS automatically
-                let filtered_files = files.filter(|f| {
-                    if let Ok(file) = f
-                        && let Some(name) = file.file_name().to_str()
-                    {
-                        return name != "System Volume Information";
-                    }
-                    true
-                });
-                if filtered_files.count() == 0 {
-                    green.apply_to("Yes")
-                } else {
-                    red.apply_to("No")
-                }
-            }
-            Err(e) => {
-                debug!("Failed to list files: {e}");
-                red.apply_to("N/A")
-            }
+        let empty_styled = match disk.empty {
+            Some(true) => green.apply_to("Yes"),
+            Some(false) => red.apply_to("No"),
+            None => red.apply_to("N/A"),
         };
 
-        let available_space = disk.available_space();
-        let available_space_styled = if available_space >= required_space {
-            green.apply_to(DecimalBytes(available_space).to_string())
+        let available_space_styled = if disk.available_space >= required_space {
+            green.apply_to(DecimalBytes(disk.available_space).to_string())
         } else {
-            red.apply_to(DecimalBytes(available_space).to_string())
+            red.apply_to(DecimalBytes(disk.available_space).to_string())
         };
 
         println!(
             "{0: <20} | {1: <35} | {2: <6} | {3: <5} | {4: >10} | {5: <5}",
-            disk.name().to_string_lossy(),
-            disk.mount_point().to_string_lossy(),
+            disk.name,
+            disk.mount_point.to_string_lossy(),
             file_system_styled,
             disk_removable_styled,
             available_space_styled,
@@ -77,42 +148,754 @@ pub fn print_disks(required_space: u64) {
     }
 }
 
-// Available disk space in current directory
-pub fn get_current_dir_available_space() -> Option<u64> {
-    let cwd_result = current_dir();
-    if cwd_result.is_err() {
+// Label of the file system of a disk, if any
+#[cfg(not(windows))]
+fn disk_label(disk: &Disk) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    if let Some(label) = udev_label(disk) {
+        return Some(label);
+    }
+    // Disks are mounted under their label otherwise, in /Volumes on macOS or in /media/<user> by udisks
+    disk.mount_point()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+// Name of a disk is its label on Windows
+#[cfg(windows)]
+fn disk_label(disk: &Disk) -> Option<String> {
+    let name = disk.name().to_string_lossy();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+// udev links labels to devices, e.g. /dev/disk/by-label/PSA -> ../../sdb1
+#[cfg(target_os = "linux")]
+fn udev_label(disk: &Disk) -> Option<String> {
+    let device = fs::canonicalize(disk.name()).ok()?;
+    fs::read_dir("/dev/disk/by-label")
+        .ok()?
+        .flatten()
+        .find(|entry| fs::canonicalize(entry.path()).is_ok_and(|target| target == device))
+        .map(|entry| unescape_label(&entry.file_name().to_string_lossy()))
+}
+
+// Decode the characters escaped by udev in links names, e.g. "PSA\x20UPDATE" for "PSA UPDATE"
+#[cfg(target_os = "linux")]
+fn unescape_label(name: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'\\'
+            && tail.first() == Some(&b'x')
+            && let Some(hex) = tail.get(1..3)
+            && let Ok(decoded) = u8::from_str_radix(&String::from_utf8_lossy(hex), 16)
+        {
+            bytes.push(decoded);
+            rest = &tail[3..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+// Criteria narrowing the automatic selection of the disk to extract updates to
+#[derive(Default)]
+pub struct DiskFilter {
+    pub label: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl DiskFilter {
+    fn issues(&self, disk: &DiskInfo) -> Vec<String> {
+        let mut issues = Vec::new();
+        if let Some(label) = &self.label
+            && !disk
+                .label
+                .as_ref()
+                .is_some_and(|disk_label| disk_label.eq_ignore_ascii_case(label))
+        {
+            issues.push(format!(
+                "label is {} instead of {label}",
+                disk.label.as_deref().unwrap_or("empty")
+            ));
+        }
+        if let Some(min_size) = self.min_size
+            && disk.total_space < min_size
+        {
+            issues.push(format!(
+                "size is {}, smaller than {}",
+                DecimalBytes(disk.total_space),
+                DecimalBytes(min_size)
+            ));
+        }
+        if let Some(max_size) = self.max_size
+            && disk.total_space > max_size
+        {
+            issues.push(format!(
+                "size is {}, larger than {}",
+                DecimalBytes(disk.total_space),
+                DecimalBytes(max_size)
+            ));
+        }
+        issues
+    }
+}
+
+// Select the only disk suitable to extract updates to: removable, empty, FAT32, with enough space, and matching the
+// filter. Fails listing the disks considered when there is no such disk or several ones. Returns its mount point.
+pub fn select_destination(required_space: u64, filter: &DiskFilter) -> Result<PathBuf, Error> {
+    select_from(list_disks(), required_space, filter)
+}
+
+fn select_from(
+    disks: Vec<DiskInfo>,
+    required_space: u64,
+    filter: &DiskFilter,
+) -> Result<PathBuf, Error> {
+    let mut candidates = Vec::new();
+    let mut rejected = Vec::new();
+    for disk in disks {
+        let mut issues = disk.suitability_issues(required_space);
+        issues.extend(filter.issues(&disk));
+        debug!("Disk {} for automatic selection: {issues:?}", disk.name);
+        if issues.is_empty() {
+            candidates.push(disk);
+        } else {
+            rejected.push((disk, issues));
+        }
+    }
+
+    let describe = |disk: &DiskInfo| {
+        format!(
+            "{} ({}, label {}, {})",
+            disk.mount_point.to_string_lossy(),
+            disk.name,
+            disk.label.as_deref().unwrap_or("empty"),
+            DecimalBytes(disk.total_space)
+        )
+    };
+    match candidates.as_slice() {
+        [disk] => {
+            debug!("Selected disk {}", describe(disk));
+            Ok(disk.mount_point.clone())
+        }
+        [] => Err(anyhow!(
+            "No disk suitable for extraction found, disks being:\n{}",
+            rejected
+                .iter()
+                .map(|(disk, issues)| format!("  {}: {}", describe(disk), issues.join(", ")))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+        _ => Err(anyhow!(
+            "Several disks suitable for extraction found, select one with --usb-label, --usb-min-size or --usb-max-size:\n{}",
+            candidates
+                .iter()
+                .map(|disk| format!("  {}", describe(disk)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+// Parse a disk size with an optional K, M, G or T suffix, in decimal units as disk sizes are listed, e.g. 32G
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    parse_bytes(value, Base::Decimal)
+        .ok_or_else(|| format!("Invalid size {value}, expecting e.g. 16G or 64GB"))
+}
+
+// Names of the files and directories in a directory
+fn list_files(path: &Path) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        // Ignore System Volume Information folder presence on Windows as it is created by the OS automatically
+        if name != "System Volume Information" {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+// Exit code when the extraction destination does not pass the checks below, in silent mode
+pub const UNSUITABLE_DESTINATION_EXIT_CODE: i32 = 3;
+
+// Error returned when the extraction destination does not pass the checks below
+#[derive(Debug)]
+pub struct UnsuitableDestination {
+    pub destination: String,
+    pub issues: Vec<String>,
+}
+
+impl fmt::Display for UnsuitableDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Destination {} is not suitable for extraction: {}",
+            self.destination,
+            self.issues.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnsuitableDestination {}
+
+// Check that a destination is suitable to extract updates to: enough space, FAT32 file system, removable disk, and
+// empty (except when resuming an extraction). Returns the issues found, if any.
+pub fn check_destination(path: &Path, required_space: u64, resuming: bool) -> Vec<String> {
+    let mut issues = Vec::new();
+    let disks = Disks::new_with_refreshed_list();
+    match find_disk(path, &disks) {
+        Some(disk) => issues.extend(DiskInfo::new(disk).issues(required_space)),
+        None => crate::interact::warn(&format!(
+            "Disk of destination {} not found, unable to check its space, file system and removability",
+            path.to_string_lossy()
+        )),
+    }
+    if !resuming {
+        match list_files(path) {
+            Ok(files) if files.is_empty() => {}
+            Ok(files) => issues.push(format!(
+                "not empty, contains {}",
+                files.iter().take(3).cloned().collect::<Vec<_>>().join(", ")
+                    + if files.len() > 3 { "..." } else { "" }
+            )),
+            Err(e) => issues.push(format!("failed to list files: {e}")),
+        }
+    }
+    debug!("Checked destination {}: {issues:?}", path.to_string_lossy());
+    issues
+}
+
+// Available disk space in the given directory
+pub fn get_available_space(path: &Path) -> Option<u64> {
+    let disks = Disks::new_with_refreshed_list();
+    find_disk(path, &disks).map(|disk| disk.available_space())
+}
+
+// Absolute path, with symbolic links resolved. On Windows, the verbatim prefix (\\?\) added by fs::canonicalize is
+// removed, so that the path can be compared with the mount points of disks (e.g. D:\)
+fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    let path = fs::canonicalize(path)?;
+    #[cfg(windows)]
+    {
+        let path_str = path.to_string_lossy();
+        if let Some(share) = path_str.strip_prefix(r"\\?\UNC\") {
+            return Ok(PathBuf::from(format!(r"\\{share}")));
+        }
+        if let Some(stripped) = path_str.strip_prefix(r"\\?\") {
+            return Ok(PathBuf::from(stripped));
+        }
+    }
+    Ok(path)
+}
+
+// Disk the given directory is stored on
+fn find_disk<'a>(path: &Path, disks: &'a Disks) -> Option<&'a Disk> {
+    let path_result = canonicalize(path);
+    if path_result.is_err() {
         debug!(
-            "Failed to retrieve information about current working directory: {}",
-            cwd_result.err().unwrap()
+            "Failed to retrieve information about directory {}: {}",
+            path.to_string_lossy(),
+            path_result.err().unwrap()
         );
         return None;
     }
-    let cwd = cwd_result.ok().unwrap();
-    let mut cwd_disk: Option<&Disk> = None;
-    // Lookup disk whose mount point is parent of cwd
-    // In case there are multiple candidates, pick up the "nearest" parent of cwd
-    let disks = Disks::new_with_refreshed_list();
-    for disk in &disks {
+    let path = path_result.ok().unwrap();
+    let mut path_disk: Option<&Disk> = None;
+    // Lookup disk whose mount point is parent of path
+    // In case there are multiple candidates, pick up the "nearest" parent of path
+    for disk in disks {
         debug!("Disk {disk:?}");
-        if cwd.starts_with(disk.mount_point())
-            && (cwd_disk.is_none()
+        if path.starts_with(disk.mount_point())
+            && (path_disk.is_none()
                 || disk
                     .mount_point()
-                    .starts_with(cwd_disk.unwrap().mount_point()))
+                    .starts_with(path_disk.unwrap().mount_point()))
         {
-            cwd_disk = Some(disk);
+            path_disk = Some(disk);
         }
     }
-    if cwd_disk.is_none() {
+    if path_disk.is_none() {
         debug!(
-            "Failed to retrieve disk information for current working directory: {}",
-            cwd.to_string_lossy()
+            "Failed to retrieve disk information for directory: {}",
+            path.to_string_lossy()
         );
         return None;
     }
     debug!(
-        "Current working directory maps to disk {}",
-        cwd_disk.unwrap().name().to_string_lossy()
+        "Directory {} maps to disk {}",
+        path.to_string_lossy(),
+        path_disk.unwrap().name().to_string_lossy()
     );
-    Some(cwd_disk.unwrap().available_space())
+    path_disk
+}
+
+// Check that a block device can be formatted: removable, and neither it nor its partitions mounted
+#[cfg(target_os = "linux")]
+pub fn check_device(device: &Path) -> Result<(), Error> {
+    let device_name = device.to_string_lossy();
+    let sys_path = sys_block_path(device)
+        .ok_or_else(|| anyhow!("Failed to find block device {device_name}"))?;
+    // Removable flag is set on the whole disk, not on its partitions
+    let sys_disk_path = if sys_path.join("partition").exists() {
+        sys_path.parent().unwrap_or(&sys_path).to_path_buf()
+    } else {
+        sys_path.clone()
+    };
+    let removable = fs::read_to_string(sys_disk_path.join("removable"))
+        .with_context(|| format!("Failed to check whether {device_name} is removable"))?;
+    if removable.trim() != "1" {
+        return Err(anyhow!(
+            "Device {device_name} is not removable, refusing to format it"
+        ));
+    }
+
+    let disks = Disks::new_with_refreshed_list();
+    for disk in &disks {
+        // Partitions of a disk are below the disk in sysfs
+        if let Some(disk_sys_path) = sys_block_path(Path::new(disk.name()))
+            && disk_sys_path.starts_with(&sys_path)
+        {
+            return Err(anyhow!(
+                "{} is mounted on {}, unmount it before formatting {device_name}",
+                disk.name().to_string_lossy(),
+                disk.mount_point().to_string_lossy()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_device(_device: &Path) -> Result<(), Error> {
+    Err(anyhow!(
+        "Formatting devices is only supported on Linux, please format the USB drive with the system"
+    ))
+}
+
+// Whether a block device is a partition rather than a whole disk
+#[cfg(target_os = "linux")]
+pub fn is_partition(device: &Path) -> bool {
+    sys_block_path(device).is_some_and(|sys_path| sys_path.join("partition").exists())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_partition(_device: &Path) -> bool {
+    false
+}
+
+// Partition mounted by psa-update. A partition mounted in a temporary directory is unmounted when dropped, releasing
+// the loop device of a disk image, and the directory is removed. A partition mounted by udisks is left mounted, to be
+// ejected by the user.
+pub struct Mount {
+    pub mount_point: PathBuf,
+    temporary: bool,
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if self.temporary {
+            unmount_temporary_directory(&self.mount_point);
+        }
+    }
+}
+
+// Mount the first partition of a device or of a disk image file, once partitioned, the partition starting at offset.
+// Relies on udisks for devices, falling back to mount (that might require root privileges) in a temporary directory.
+#[cfg(target_os = "linux")]
+pub fn mount_first_partition(device: &Path, offset: u64) -> Result<Mount, Error> {
+    let device_name = device.to_string_lossy();
+    if fs::metadata(device).is_ok_and(|metadata| metadata.is_file()) {
+        return mount_in_temporary_directory(
+            &device_name,
+            &["-o", &format!("loop,offset={offset}")],
+        );
+    }
+
+    reread_partition_table(device)?;
+    let partition = partition_path(device)?;
+    // Partition device is created asynchronously by udev
+    for _ in 0..50 {
+        if partition.exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let partition_name = partition.to_string_lossy();
+
+    match run_command("udisksctl", &["mount", "--block-device", &partition_name]) {
+        // e.g. "Mounted /dev/sdb1 at /media/user/PSA"
+        Ok(output) => output
+            .trim()
+            .trim_end_matches('.')
+            .split_once(" at ")
+            .map(|(_, mount_point)| Mount {
+                mount_point: PathBuf::from(mount_point),
+                temporary: false,
+            })
+            .ok_or_else(|| anyhow!("Unexpected output of udisksctl: {output}")),
+        Err(e) => {
+            debug!("Failed to mount {partition_name} with udisksctl: {e:#}");
+            mount_in_temporary_directory(&partition_name, &[])
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mount_first_partition(_device: &Path, _offset: u64) -> Result<Mount, Error> {
+    Err(anyhow!("Mounting the USB drive is only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn mount_in_temporary_directory(source: &str, options: &[&str]) -> Result<Mount, Error> {
+    let mount_point = std::env::temp_dir().join(format!("psa-update-{}", std::process::id()));
+    fs::create_dir_all(&mount_point).with_context(|| {
+        format!(
+            "Failed to create mount point {}",
+            mount_point.to_string_lossy()
+        )
+    })?;
+    let mount_point_name = mount_point.to_string_lossy().to_string();
+    let mut args = options.to_vec();
+    args.extend([source, &mount_point_name]);
+    if let Err(e) = run_command("mount", &args) {
+        let _ = fs::remove_dir(&mount_point);
+        return Err(e.context(format!("Failed to mount {source}")));
+    }
+    Ok(Mount {
+        mount_point,
+        temporary: true,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn unmount_temporary_directory(mount_point: &Path) {
+    let mount_point_name = mount_point.to_string_lossy();
+    // Already unmounted when ejected
+    if fs::remove_dir(mount_point).is_ok() {
+        return;
+    }
+    debug!("Unmounting {mount_point_name}");
+    if let Err(e) = run_command("umount", &["--detach-loop", &mount_point_name]) {
+        crate::interact::warn(&format!("Failed to unmount {mount_point_name}: {e:#}"));
+        return;
+    }
+    if let Err(e) = fs::remove_dir(mount_point) {
+        debug!("Failed to remove mount point {mount_point_name}: {e}");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount_temporary_directory(_mount_point: &Path) {}
+
+// Ask the kernel to read the partition table of a disk again, once written
+#[cfg(target_os = "linux")]
+fn reread_partition_table(device: &Path) -> Result<(), Error> {
+    use std::os::fd::AsRawFd;
+    // _IO(0x12, 95), not defined by libc
+    const BLKRRPART: libc::Ioctl = 0x125F;
+    let file = File::open(device)
+        .with_context(|| format!("Failed to open {}", device.to_string_lossy()))?;
+    debug!("Reading partition table of {}", device.to_string_lossy());
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKRRPART) } != 0 {
+        return Err(Error::new(std::io::Error::last_os_error()).context(format!(
+            "Failed to read partition table of {}",
+            device.to_string_lossy()
+        )));
+    }
+    Ok(())
+}
+
+// Device of the first partition of a disk, e.g. /dev/sdb1 for /dev/sdb or /dev/mmcblk0p1 for /dev/mmcblk0
+#[cfg(target_os = "linux")]
+fn partition_path(device: &Path) -> Result<PathBuf, Error> {
+    let device = fs::canonicalize(device)
+        .with_context(|| format!("Failed to find device {}", device.to_string_lossy()))?;
+    let device_name = device.to_string_lossy();
+    if device_name.ends_with(|c: char| c.is_ascii_digit()) {
+        Ok(PathBuf::from(format!("{device_name}p1")))
+    } else {
+        Ok(PathBuf::from(format!("{device_name}1")))
+    }
+}
+
+// Path of a block device in sysfs, e.g. /sys/devices/.../block/sdb/sdb1 for /dev/sdb1
+#[cfg(target_os = "linux")]
+fn sys_block_path(device: &Path) -> Option<PathBuf> {
+    let device = fs::canonicalize(device).ok()?;
+    let name = device.file_name()?;
+    fs::canonicalize(Path::new("/sys/class/block").join(name)).ok()
+}
+
+// Write the directory entries of a directory to the disk, e.g. names of the files created in it
+#[cfg(unix)]
+pub fn sync_directory(path: &Path) -> Result<(), Error> {
+    File::open(path)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("Failed to sync directory {}", path.to_string_lossy()))
+}
+
+// Directories cannot be opened as files on Windows, their entries being written along with the files
+#[cfg(not(unix))]
+pub fn sync_directory(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+// Write to the disk all data of the file system of the given directory that is still in memory
+#[cfg(target_os = "linux")]
+pub fn sync_file_system(path: &Path) -> Result<(), Error> {
+    use std::os::fd::AsRawFd;
+    let directory = File::open(path)
+        .with_context(|| format!("Failed to open directory {}", path.to_string_lossy()))?;
+    debug!("Syncing file system of {}", path.to_string_lossy());
+    if unsafe { libc::syncfs(directory.as_raw_fd()) } != 0 {
+        return Err(Error::new(std::io::Error::last_os_error()).context(format!(
+            "Failed to sync file system of {}",
+            path.to_string_lossy()
+        )));
+    }
+    Ok(())
+}
+
+// Files and directories are synced one by one on other systems
+#[cfg(not(target_os = "linux"))]
+pub fn sync_file_system(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+// Unmount the file system of the given directory and power off its device, so that it can be unplugged safely.
+// Relies on udisks, falling back to umount (that might require root privileges).
+#[cfg(target_os = "linux")]
+pub fn eject(path: &Path) -> Result<(), Error> {
+    let disks = Disks::new_with_refreshed_list();
+    let disk = find_disk(path, &disks)
+        .ok_or_else(|| anyhow!("Failed to find the disk of {}", path.to_string_lossy()))?;
+    let device = disk.name().to_string_lossy().to_string();
+    let mount_point = disk.mount_point().to_string_lossy().to_string();
+    if mount_point == "/" {
+        return Err(anyhow!("Refusing to unmount the root file system"));
+    }
+
+    match run_command("udisksctl", &["unmount", "--block-device", &device]) {
+        Ok(_) => {
+            // The device is unmounted at this point, powering it off is a nice to have
+            if let Err(e) = run_command("udisksctl", &["power-off", "--block-device", &device]) {
+                debug!("Failed to power off {device}: {e:#}");
+            }
+            Ok(())
+        }
+        Err(e) => {
+            debug!("Failed to unmount {device} with udisksctl: {e:#}");
+            run_command("umount", &[&mount_point])
+                .map(|_| ())
+                .with_context(|| format!("Failed to unmount {mount_point}"))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn eject(_path: &Path) -> Result<(), Error> {
+    Err(anyhow!(
+        "Ejecting the USB drive is only supported on Linux, please eject it from the system"
+    ))
+}
+
+// Run a command, returning its output
+#[cfg(target_os = "linux")]
+fn run_command(program: &str, args: &[&str]) -> Result<String, Error> {
+    debug!("Running {program} {}", args.join(" "));
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Evict the content of a file from the page cache, so that it is read again from the disk rather than from memory.
+// Only supported on Linux, content might be read from memory on other systems.
+#[cfg(target_os = "linux")]
+pub fn drop_cache(file: &File) {
+    use std::os::fd::AsRawFd;
+    // Pages not yet written to the disk cannot be evicted
+    if let Err(e) = file.sync_data() {
+        debug!("Failed to sync file: {e}");
+    }
+    let result = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if result != 0 {
+        debug!("Failed to evict file from page cache: error {result}");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_cache(_file: &File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1_000_000_000;
+
+    // Empty removable FAT32 disk
+    fn usb_disk(name: &str, label: Option<&str>, size: u64) -> DiskInfo {
+        DiskInfo {
+            name: name.to_string(),
+            mount_point: PathBuf::from(format!("/media/{name}")),
+            file_system: "vfat".to_string(),
+            label: label.map(str::to_string),
+            removable: true,
+            total_space: size,
+            available_space: size,
+            empty: Some(true),
+        }
+    }
+
+    fn system_disk() -> DiskInfo {
+        DiskInfo {
+            name: "/dev/nvme0n1p2".to_string(),
+            mount_point: PathBuf::from("/"),
+            file_system: "ext4".to_string(),
+            label: None,
+            removable: false,
+            total_space: 500 * GB,
+            available_space: 200 * GB,
+            empty: Some(false),
+        }
+    }
+
+    #[test]
+    fn suitability_issues_are_listed() {
+        assert!(
+            usb_disk("sdb1", None, 32 * GB)
+                .suitability_issues(10 * GB)
+                .is_empty()
+        );
+        assert_eq!(
+            system_disk().suitability_issues(10 * GB),
+            vec![
+                "file system is ext4 instead of FAT32",
+                "disk /dev/nvme0n1p2 is not removable",
+                "not empty"
+            ]
+        );
+        let mut disk = usb_disk("sdb1", None, 8 * GB);
+        disk.empty = None;
+        assert_eq!(
+            disk.suitability_issues(10 * GB),
+            vec![
+                "not enough space, 8.00 GB available but 10.00 GB required",
+                "failed to list files"
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_matches_label_and_size() {
+        let disk = usb_disk("sdb1", Some("PSA"), 32 * GB);
+        let filter = |label: Option<&str>, min_size, max_size| DiskFilter {
+            label: label.map(str::to_string),
+            min_size,
+            max_size,
+        };
+        assert!(DiskFilter::default().issues(&disk).is_empty());
+        assert!(
+            filter(Some("psa"), Some(16 * GB), Some(64 * GB))
+                .issues(&disk)
+                .is_empty()
+        );
+        assert_eq!(
+            filter(Some("UPDATE"), None, None).issues(&disk),
+            vec!["label is PSA instead of UPDATE"]
+        );
+        assert_eq!(
+            filter(Some("PSA"), None, None).issues(&usb_disk("sdc1", None, 32 * GB)),
+            vec!["label is empty instead of PSA"]
+        );
+        assert_eq!(
+            filter(None, Some(64 * GB), None).issues(&disk),
+            vec!["size is 32.00 GB, smaller than 64.00 GB"]
+        );
+        assert_eq!(
+            filter(None, None, Some(16 * GB)).issues(&disk),
+            vec!["size is 32.00 GB, larger than 16.00 GB"]
+        );
+    }
+
+    #[test]
+    fn only_suitable_disk_is_selected() {
+        let disks = vec![system_disk(), usb_disk("sdb1", None, 32 * GB)];
+        let selected = select_from(disks, 10 * GB, &DiskFilter::default()).unwrap();
+        assert_eq!(selected, PathBuf::from("/media/sdb1"));
+    }
+
+    #[test]
+    fn no_suitable_disk_is_an_error() {
+        let disks = vec![system_disk(), usb_disk("sdb1", None, 8 * GB)];
+        let error = select_from(disks, 10 * GB, &DiskFilter::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("No disk suitable for extraction found"));
+        assert!(error.contains("/media/sdb1 (sdb1, label empty, 8.00 GB): not enough space"));
+        assert!(select_from(Vec::new(), 0, &DiskFilter::default()).is_err());
+    }
+
+    #[test]
+    fn several_suitable_disks_are_narrowed_by_filter() {
+        let disks = || {
+            vec![
+                usb_disk("sdb1", Some("PSA"), 32 * GB),
+                usb_disk("sdc1", Some("BACKUP"), 64 * GB),
+            ]
+        };
+        let error = select_from(disks(), 10 * GB, &DiskFilter::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Several disks suitable for extraction found"));
+        assert!(error.contains("/media/sdb1") && error.contains("/media/sdc1"));
+
+        let by_label = DiskFilter {
+            label: Some("psa".to_string()),
+            ..DiskFilter::default()
+        };
+        assert_eq!(
+            select_from(disks(), 10 * GB, &by_label).unwrap(),
+            PathBuf::from("/media/sdb1")
+        );
+        let by_min_size = DiskFilter {
+            min_size: Some(48 * GB),
+            ..DiskFilter::default()
+        };
+        assert_eq!(
+            select_from(disks(), 10 * GB, &by_min_size).unwrap(),
+            PathBuf::from("/media/sdc1")
+        );
+        let by_max_size = DiskFilter {
+            max_size: Some(48 * GB),
+            ..DiskFilter::default()
+        };
+        assert_eq!(
+            select_from(disks(), 10 * GB, &by_max_size).unwrap(),
+            PathBuf::from("/media/sdb1")
+        );
+    }
+
+    #[test]
+    fn sizes_are_decimal() {
+        assert_eq!(parse_size("32G"), Ok(32 * GB));
+        assert_eq!(parse_size("64GB"), Ok(64 * GB));
+        assert_eq!(
+            parse_size("32GiB"),
+            Err("Invalid size 32GiB, expecting e.g. 16G or 64GB".to_string())
+        );
+    }
 }
@@ -1,24 +1,167 @@
+use std::fmt;
+use std::io::{ErrorKind, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::time::Duration;
+
 use tokio::fs;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
 
 use log::debug;
 
 use anyhow::{Context, Error, Result, anyhow};
 
-use reqwest::header::{ACCEPT_RANGES, RANGE};
-use reqwest::{Client, Response};
+use reqwest::header::{ACCEPT_RANGES, ETAG, HeaderMap, HeaderName, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{Client, Response, StatusCode};
 
 use futures_util::StreamExt;
+use futures_util::future::join_all;
+
+use console::style;
 
-use indicatif::MultiProgress;
+use indicatif::{DecimalBytes, MultiProgress, ProgressBar};
 
+use crate::checksum;
 use crate::interact;
+use crate::interrupt;
+use crate::throttle::RateLimiter;
+
+// Minimum size of a segment when downloading a file using multiple connections
+const MIN_SEGMENT_SIZE: u64 = 10 * 1024 * 1024;
+
+// Download state of a segment is saved every time this amount of data is downloaded
+const SEGMENT_SAVE_INTERVAL: u64 = 16 * 1024 * 1024;
+
+// Upper bound of the delay between two retries, whatever the number of attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+pub struct DownloadOptions {
+    // Directory where updates are downloaded, in a subdirectory per update
+    pub download_dir: PathBuf,
+    // Number of connections used to download a single file, in segments
+    pub connections: u64,
+    // Number of consecutive retries after a transient error, before giving up
+    pub retries: u32,
+    // Delay before the first retry, doubled on every consecutive retry
+    pub retry_delay: Duration,
+    // Shared by all downloads
+    pub rate_limiter: RateLimiter,
+}
+
+// Retries of the requests of a download after transient errors: network errors, 429 (too many requests) and 5xx
+// statuses. Delay between retries increases exponentially. Attempts are reset once data is received again.
+struct Retry<'a> {
+    options: &'a DownloadOptions,
+    multi_progress: &'a MultiProgress,
+    attempt: u32,
+}
+
+impl<'a> Retry<'a> {
+    fn new(options: &'a DownloadOptions, multi_progress: &'a MultiProgress) -> Retry<'a> {
+        Retry {
+            options,
+            multi_progress,
+            attempt: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    // Wait before retrying after the given error. The error is returned when it is not transient or when all
+    // retries have been attempted.
+    async fn wait(&mut self, error: Error) -> Result<(), Error> {
+        if self.attempt >= self.options.retries || !is_transient(&error) {
+            return Err(error);
+        }
+        self.attempt += 1;
+        let delay = self
+            .options
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(self.attempt - 1))
+            .min(MAX_RETRY_DELAY);
+        debug!("Retrying in {delay:?} after error: {error:?}");
+        self.multi_progress.println(format!(
+            "{} {error}, retrying in {}s ({}/{})",
+            style("[warning]").yellow(),
+            delay.as_secs_f32(),
+            self.attempt,
+            self.options.retries
+        ))?;
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => Ok(()),
+            _ = interrupt::wait() => Err(Error::new(interrupt::Interrupted)),
+        }
+    }
+
+    // Run a request, retrying it after transient errors
+    async fn run<T, F, Fut>(&mut self, request: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        loop {
+            let result = tokio::select! {
+                biased;
+                _ = interrupt::wait() => return Err(Error::new(interrupt::Interrupted)),
+                result = request() => result,
+            };
+            match result {
+                Ok(result) => return Ok(result),
+                Err(e) => self.wait(e).await?,
+            }
+        }
+    }
+}
+
+// Error returned when a response ends before all the requested content was received, e.g. connection closed by the
+// server or a proxy
+#[derive(Debug)]
+struct TruncatedResponse {
+    filename: String,
+}
+
+impl fmt::Display for TruncatedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to download segment of file {}, received less content than expected",
+            self.filename
+        )
+    }
+}
+
+impl std::error::Error for TruncatedResponse {}
+
+// Whether an error is worth retrying: network errors, truncated responses and 429 or 5xx statuses
+fn is_transient(error: &Error) -> bool {
+    if error.is::<TruncatedResponse>() {
+        return true;
+    }
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) => match e.status() {
+            Some(status) => status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            None => {
+                e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() || e.is_decode()
+            }
+        },
+        None => false,
+    }
+}
 
 pub struct FileDownloadInfo {
     pub filename: String,
     pub filesize: u64,
     pub supports_resume: bool,
+    pub digests: Vec<checksum::ServerDigest>,
+    // Validators of the remote file, identifying its version
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
 }
 
 // Issue a head request to retrieve info on file to download
@@ -31,131 +174,889 @@ pub async fn request_file_download_info(
     let head_response = client.get(url).send().await?;
     debug!("Received response {head_response:?}");
 
-    if head_response.status() != 200 {
-        return Err(anyhow!(
-            "Failed to fetch file information, got status {}.",
-            head_response.status()
-        ));
-    }
+    let head_response = check_status(head_response, "Failed to fetch file information")?;
 
     // Parse target filename from response
     let filename = String::from(parse_filename(&head_response)?);
     let filesize = head_response.content_length().unwrap_or(0);
     let supports_resume = head_response.headers().contains_key(ACCEPT_RANGES);
+    let digests = checksum::parse_server_digests(head_response.headers());
+    let etag = header_string(head_response.headers(), ETAG);
+    let last_modified = header_string(head_response.headers(), LAST_MODIFIED);
 
     Ok(FileDownloadInfo {
         filename,
         filesize,
         supports_resume,
+        digests,
+        etag,
+        last_modified,
     })
 }
 
-// Could not find a suitable crate to download a file that supports for resume
+fn header_string(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+// Could not find a suitable crate to download a file that supports for resume.
+// Content is written to <filename>.part, renamed to filename once the download is complete and verified.
+// Returns the path of the downloaded file in directory.
 pub async fn download_file(
     client: &Client,
     url: &str,
+    directory: &Path,
     multi_progress: &MultiProgress,
     try_to_resume: bool,
+    options: &DownloadOptions,
 ) -> Result<String, Error> {
     let mut resume_position: u64 = 0; // Greater than zero means we will resume download
     let mut head_content_length: u64 = 0;
+    let mut head_digests = Vec::new();
+    // State saved next to the file while downloading, when the download can be resumed
+    let mut state = None;
+    let mut retry = Retry::new(options, multi_progress);
 
     if try_to_resume {
         // Issuing a HEAD request to retrieve download name and size
-        let file_info = request_file_download_info(client, url).await?;
+        let file_info = retry
+            .run(|| request_file_download_info(client, url))
+            .await?;
+        let filename = &local_filename(directory, &file_info.filename);
+        let part_filename = part_filename(filename);
+        head_content_length = file_info.filesize;
+        head_digests = file_info.digests.clone();
+
+        if head_content_length > 0
+            && fs::metadata(filename)
+                .await
+                .is_ok_and(|metadata| metadata.len() == head_content_length)
+        {
+            println!("Skipping download of file {filename}, already completed");
+            // Downloaded by a previous version not recording checksums
+            if !checksum::has_manifest(filename) {
+                checksum::record_download(filename, &head_digests, multi_progress).await?;
+            }
+            return Ok(filename.clone());
+        }
+
+        let saved_state = load_download_state(filename).await;
+        let part_metadata = fs::metadata(&part_filename).await;
 
         if !file_info.supports_resume {
             debug!("Server does support range header");
+        } else if head_content_length == 0 {
+            // A partial file cannot be told apart from a complete one, downloading it again without saving a state
+            debug!("Size of file {filename} is unknown, download cannot be resumed");
+            remove_download_state(filename).await?;
+        } else if saved_state
+            .as_ref()
+            .is_some_and(|state| !state.segments.is_empty())
+            || (options.connections > 1 && part_metadata.is_err())
+        {
+            // Resuming a segmented download, or starting a new one
+            return download_file_in_segments(
+                client,
+                url,
+                &file_info,
+                filename,
+                saved_state,
+                multi_progress,
+                options,
+            )
+            .await;
         } else {
-            let file_metadata = fs::metadata(&file_info.filename).await;
-            if file_metadata.is_ok() {
-                resume_position = file_metadata.ok().unwrap().len();
-                debug!(
-                    "File {} exists with size: {}",
-                    &file_info.filename, resume_position
-                );
-
-                head_content_length = file_info.filesize;
-                if head_content_length == resume_position {
+            // Without a saved state, a complete file cannot be told apart from a file allocated by a segmented download
+            let has_saved_state = saved_state.is_some();
+            let saved_state = match saved_state {
+                Some(saved_state) if !saved_state.matches(&file_info) => {
                     println!(
-                        "Skipping download of file {}, already completed",
-                        file_info.filename
+                        "File {filename} changed on the server since its download started, downloading it again"
+                    );
+                    None
+                }
+                Some(saved_state) if !saved_state.segments.is_empty() => {
+                    // Allocated by a segmented download, content is not contiguous and cannot be resumed
+                    debug!("File {part_filename} was downloaded in segments, downloading it again");
+                    None
+                }
+                Some(saved_state) => Some(saved_state),
+                None if part_metadata.is_ok() => {
+                    debug!(
+                        "No download state for file {part_filename}, remote file cannot be checked for changes"
                     );
-                    return Ok(file_info.filename);
+                    Some(DownloadState::new(&file_info))
                 }
+                None => None,
+            };
+            if let Some(saved_state) = saved_state {
+                // A file larger than the remote one cannot be resumed
+                if let Ok(part_metadata) = part_metadata
+                    && part_metadata.len() <= head_content_length
+                {
+                    resume_position = part_metadata.len();
+                    debug!("File {part_filename} exists with size: {resume_position}");
+                }
+
+                if resume_position == head_content_length && has_saved_state {
+                    // Interrupted before being verified
+                    complete_download(filename, head_content_length, &head_digests, multi_progress)
+                        .await?;
+                    return Ok(filename.clone());
+                } else if resume_position == head_content_length {
+                    debug!(
+                        "File {part_filename} is complete but has no download state, downloading it again"
+                    );
+                    resume_position = 0;
+                }
+                state = Some(saved_state);
+            } else {
+                state = Some(DownloadState::new(&file_info));
             }
         }
     }
 
-    let mut request = client.get(url);
-    if resume_position > 0 {
-        debug!("Adding range header to resume download: bytes={resume_position}-");
-        request = request.header(RANGE, format!("bytes={resume_position}-"));
+    let if_range = state
+        .as_ref()
+        .and_then(DownloadState::if_range)
+        .map(str::to_string);
+    let response = retry
+        .run(|| send_download_request(client, url, resume_position, None, if_range.as_deref()))
+        .await?;
+    if resume_position > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+        // If-Range not matching, whole file is sent
+        println!("File changed on the server since its download started, downloading it again");
+        resume_position = 0;
     }
 
-    debug!("Sending request GET {url}");
-    let response = request.send().await?;
-    debug!("Received response {response:?}");
-
-    if !response.status().is_success() {
-        return Err(anyhow!(
-            "Failed to download file, got status {}.",
-            response.status()
-        ));
+    // Parse target filename from response
+    let filename = local_filename(directory, parse_filename(&response)?);
+    let part_filename = part_filename(&filename);
+    if let Some(state) = &mut state {
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            state.set_validators(response.headers());
+        } else {
+            state.restart(&response);
+        }
+        save_download_state(&filename, state).await?;
     }
 
-    // Parse target filename from response
-    let filename = String::from(parse_filename(&response)?);
+    // Digests sent along with a partial content are those of the part, relying on HEAD response instead
+    let mut digests = if response.status() == StatusCode::PARTIAL_CONTENT {
+        head_digests
+    } else {
+        checksum::parse_server_digests(response.headers())
+    };
 
     let remaining_content_length = response.content_length().unwrap_or(0);
-    let total_content_length = if resume_position > 0 {
+    let mut total_content_length = if resume_position > 0 {
         head_content_length // content length retrieved on HEAD request in case of download resume
     } else {
         remaining_content_length
     };
 
-    let progress_bar = multi_progress.add(interact::progress_bar(total_content_length));
-    progress_bar.set_message(filename.to_string()); // Triggers first draw
-    progress_bar.set_position(resume_position);
-    // Need to reset ETA in case of resume, otherwise estimations are biased
-    progress_bar.reset_eta();
+    let progress_bar = add_progress_bar(
+        multi_progress,
+        total_content_length,
+        &filename,
+        resume_position,
+    );
 
     let file = if resume_position == 0 {
-        debug!("Opening {filename} in create mode");
-        File::create(filename.clone())
+        debug!("Opening {part_filename} in create mode");
+        File::create(&part_filename)
             .await
-            .with_context(|| format!("Failed to create file {filename}"))?
+            .with_context(|| format!("Failed to create file {part_filename}"))?
     } else {
-        debug!("Opening {filename} in append mode for resume");
+        debug!("Opening {part_filename} in append mode for resume");
         OpenOptions::new()
             .append(true)
-            .open(filename.clone())
+            .open(&part_filename)
             .await
-            .with_context(|| format!("Failed to open file {filename} in append mode"))?
+            .with_context(|| format!("Failed to open file {part_filename} in append mode"))?
     };
 
     let mut stream = response.bytes_stream();
 
     let mut file_writer = BufWriter::new(file);
+    let mut position = resume_position;
 
-    while let Some(item) = stream.next().await {
-        let chunk =
-            item.with_context(|| format!("Failed to download file {filename} from {url}"))?;
-        progress_bar.inc(chunk.len() as u64);
-        file_writer
-            .write_all(&chunk)
+    let result = loop {
+        let next = tokio::select! {
+            biased;
+            _ = interrupt::wait() => break Err(Error::new(interrupt::Interrupted)),
+            next = stream.next() => next,
+        };
+        let error = match next {
+            Some(Ok(chunk)) => {
+                progress_bar.inc(chunk.len() as u64);
+                file_writer
+                    .write_all(&chunk)
+                    .await
+                    .with_context(|| format!("Error writing to file {part_filename}"))?;
+                position += chunk.len() as u64;
+                retry.reset();
+                options.rate_limiter.consume(chunk.len() as u64).await;
+                continue;
+            }
+            Some(Err(e)) => {
+                Error::new(e).context(format!("Failed to download file {filename} from {url}"))
+            }
+            None => break Ok(()),
+        };
+
+        // Resuming from the current position
+        if let Err(e) = retry.wait(error).await {
+            break Err(e);
+        }
+        let if_range = state
+            .as_ref()
+            .and_then(DownloadState::if_range)
+            .map(str::to_string);
+        let response = match retry
+            .run(|| send_download_request(client, url, position, None, if_range.as_deref()))
             .await
-            .with_context(|| format!("Error writing to file {filename}"))?;
-    }
+        {
+            Ok(response) => response,
+            Err(e) => break Err(e),
+        };
+        if position > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            // Range not supported by the server or remote file changed, downloading the whole file again
+            debug!("Range not satisfied by the server, restarting download of {filename}");
+            file_writer
+                .flush()
+                .await
+                .with_context(|| format!("Error flushing file {part_filename}"))?;
+            let file = file_writer.get_mut();
+            file.set_len(0)
+                .await
+                .with_context(|| format!("Failed to truncate file {part_filename}"))?;
+            file.seek(SeekFrom::Start(0))
+                .await
+                .with_context(|| format!("Failed to seek in file {part_filename}"))?;
+            position = 0;
+            total_content_length = response.content_length().unwrap_or(0);
+            progress_bar.set_length(total_content_length);
+            progress_bar.set_position(0);
+            progress_bar.reset_eta();
+            digests = checksum::parse_server_digests(response.headers());
+            if let Some(state) = &mut state {
+                state.restart(&response);
+                save_download_state(&filename, state).await?;
+            }
+        }
+        stream = response.bytes_stream();
+    };
+    // Whatever the result, so that the download resumes from the end of the content written to the .part file
     file_writer
         .flush()
         .await
-        .with_context(|| format!("Error flushing file {filename}"))?;
+        .with_context(|| format!("Error flushing file {part_filename}"))?;
+    if let Err(e) = result {
+        if interrupt::is_interruption(&e) {
+            progress_bar.abandon();
+            return Err(e.context(format!(
+                "{} of {} downloaded to {part_filename}",
+                DecimalBytes(position),
+                DecimalBytes(total_content_length)
+            )));
+        }
+        return Err(e);
+    }
+
+    progress_bar.finish();
+
+    complete_download(&filename, total_content_length, &digests, multi_progress).await?;
+    Ok(filename)
+}
+
+fn local_filename(directory: &Path, name: &str) -> String {
+    directory.join(name).to_string_lossy().to_string()
+}
+
+fn part_filename(filename: &str) -> String {
+    format!("{filename}.part")
+}
+
+// Verify the length and checksum of a downloaded <filename>.part, and rename it to filename
+async fn complete_download(
+    filename: &str,
+    expected_size: u64,
+    digests: &[checksum::ServerDigest],
+    multi_progress: &MultiProgress,
+) -> Result<(), Error> {
+    let part_filename = part_filename(filename);
+    let size = fs::metadata(&part_filename)
+        .await
+        .with_context(|| format!("Failed to get metadata of file {part_filename}"))?
+        .len();
+    // Size is unknown when the server does not send the content length, a resumed download cannot be verified then
+    if expected_size == 0
+        && fs::metadata(download_state_filename(filename))
+            .await
+            .is_ok()
+    {
+        return Err(anyhow!(
+            "Cannot verify that downloaded file {part_filename} is complete, its size being unknown. Please delete it and download it again."
+        ));
+    }
+    if expected_size > 0 && size != expected_size {
+        return Err(anyhow!(
+            "Downloaded file {part_filename} is incomplete: expected {expected_size} bytes but got {size}"
+        ));
+    }
+
+    let hashes = checksum::check_download(&part_filename, digests, multi_progress).await?;
+    debug!("Renaming {part_filename} to {filename}");
+    fs::rename(&part_filename, filename)
+        .await
+        .with_context(|| format!("Failed to rename {part_filename} to {filename}"))?;
+    checksum::write_manifest(filename, &hashes.sha256)?;
+    remove_download_state(filename).await
+}
+
+// Download a file without storing it: its content is sent in chunks to the given channel, e.g. to be extracted on
+// the fly. Download stops early when the receiver is dropped. Returns the name of the downloaded file.
+pub async fn stream_file(
+    client: &Client,
+    url: &str,
+    multi_progress: &MultiProgress,
+    sender: SyncSender<Vec<u8>>,
+    options: &DownloadOptions,
+) -> Result<String, Error> {
+    let mut retry = Retry::new(options, multi_progress);
+    let response = retry
+        .run(|| send_download_request(client, url, 0, None, None))
+        .await?;
 
+    let filename = String::from(parse_filename(&response)?);
+    let digests = checksum::parse_server_digests(response.headers());
+    let etag = header_string(response.headers(), ETAG);
+    let last_modified = header_string(response.headers(), LAST_MODIFIED);
+    let if_range = if_range_value(etag.as_deref(), last_modified.as_deref());
+    let content_length = response.content_length().unwrap_or(0);
+
+    let progress_bar = add_progress_bar(multi_progress, content_length, &filename, 0);
+
+    let mut stream = response.bytes_stream();
+    let mut hasher = checksum::Hasher::default();
+    let mut position = 0;
+    loop {
+        let next = tokio::select! {
+            biased;
+            _ = interrupt::wait() => {
+                progress_bar.abandon();
+                return Err(Error::new(interrupt::Interrupted));
+            }
+            next = stream.next() => next,
+        };
+        let error = match next {
+            Some(Ok(chunk)) => {
+                progress_bar.inc(chunk.len() as u64);
+                hasher.update(&chunk);
+                position += chunk.len() as u64;
+                retry.reset();
+                options.rate_limiter.consume(chunk.len() as u64).await;
+                // Sending blocks when the receiver is late, not to buffer the whole file in memory
+                let sender = sender.clone();
+                let sent = tokio::task::block_in_place(move || sender.send(chunk.to_vec()));
+                if sent.is_err() {
+                    debug!("Download of file {filename} stopped, content is no longer consumed");
+                    progress_bar.abandon();
+                    return Ok(filename);
+                }
+                continue;
+            }
+            Some(Err(e)) => {
+                Error::new(e).context(format!("Failed to download file {filename} from {url}"))
+            }
+            None => break,
+        };
+
+        // Resuming from the current position, content already sent cannot be sent again
+        retry.wait(error).await?;
+        let response = retry
+            .run(|| send_download_request(client, url, position, None, if_range))
+            .await?;
+        if position > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(anyhow!(
+                "Failed to resume download of file {filename}, server does not support range header or file changed on the server"
+            ));
+        }
+        stream = response.bytes_stream();
+    }
     progress_bar.finish();
+
+    checksum::check_server_digests(&filename, &hasher.finalize(), &digests)?;
     Ok(filename)
 }
 
+// Blocking reader of the chunks sent by stream_file
+pub struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl ChannelReader {
+    pub fn new(receiver: Receiver<Vec<u8>>) -> ChannelReader {
+        ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                // Sender dropped: end of download
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+// State of a download, saved next to the downloaded file so that it can be resumed safely: version of the remote
+// file when the download started, and progress of each segment when downloading in segments
+#[derive(Debug, Serialize, Deserialize)]
+struct DownloadState {
+    filesize: u64,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+    // Empty unless downloading in segments
+    #[serde(default)]
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Segment {
+    start: u64,
+    // Exclusive
+    end: u64,
+    downloaded: u64,
+}
+
+impl Segment {
+    // Position in the file where the download of the segment resumes
+    fn position(&self) -> u64 {
+        self.start + self.downloaded
+    }
+
+    fn is_complete(&self) -> bool {
+        self.position() >= self.end
+    }
+}
+
+impl DownloadState {
+    fn new(file_info: &FileDownloadInfo) -> DownloadState {
+        DownloadState {
+            filesize: file_info.filesize,
+            etag: file_info.etag.clone(),
+            last_modified: file_info.last_modified.clone(),
+            segments: Vec::new(),
+        }
+    }
+
+    // Split the file in segments of equal size
+    fn split(&mut self, connections: u64) {
+        let filesize = self.filesize;
+        let count = connections.min(filesize / MIN_SEGMENT_SIZE).max(1);
+        let segment_size = filesize.div_ceil(count);
+        self.segments = (0..count)
+            .map(|i| Segment {
+                start: i * segment_size,
+                end: ((i + 1) * segment_size).min(filesize),
+                downloaded: 0,
+            })
+            .collect();
+    }
+
+    // Whether the remote file is still the one being downloaded
+    fn matches(&self, file_info: &FileDownloadInfo) -> bool {
+        self.filesize == file_info.filesize
+            && self.etag == file_info.etag
+            && self.last_modified == file_info.last_modified
+    }
+
+    fn set_validators(&mut self, headers: &HeaderMap) {
+        self.etag = header_string(headers, ETAG);
+        self.last_modified = header_string(headers, LAST_MODIFIED);
+    }
+
+    // Start over with the whole file sent by the server, which might be another version of different size
+    fn restart(&mut self, response: &Response) {
+        self.filesize = response.content_length().unwrap_or(0);
+        self.set_validators(response.headers());
+        if !self.segments.is_empty() {
+            self.split(self.segments.len() as u64);
+        }
+    }
+
+    fn if_range(&self) -> Option<&str> {
+        if_range_value(self.etag.as_deref(), self.last_modified.as_deref())
+    }
+
+    fn downloaded(&self) -> u64 {
+        self.segments.iter().map(|s| s.downloaded).sum()
+    }
+}
+
+fn download_state_filename(filename: &str) -> String {
+    format!("{filename}.download")
+}
+
+async fn load_download_state(filename: &str) -> Option<DownloadState> {
+    let state_filename = download_state_filename(filename);
+    let content = fs::read_to_string(&state_filename).await.ok()?;
+    match serde_json::from_str(&content) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            debug!("Ignoring invalid download state {state_filename}: {e}");
+            None
+        }
+    }
+}
+
+async fn remove_download_state(filename: &str) -> Result<(), Error> {
+    let state_filename = download_state_filename(filename);
+    match fs::remove_file(&state_filename).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(Error::new(e).context(format!("Failed to remove download state {state_filename}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn save_download_state(filename: &str, state: &DownloadState) -> Result<(), Error> {
+    let state_filename = download_state_filename(filename);
+    let content = serde_json::to_string(state).context("Failed to serialize download state")?;
+    // Writing to a temporary file first, not to lose the state if interrupted while writing
+    let temporary_filename = format!("{state_filename}.tmp");
+    fs::write(&temporary_filename, content)
+        .await
+        .with_context(|| format!("Failed to write download state {temporary_filename}"))?;
+    fs::rename(&temporary_filename, &state_filename)
+        .await
+        .with_context(|| format!("Failed to write download state {state_filename}"))
+}
+
+// Download a file using multiple connections, each one downloading a segment (range of bytes) of the file
+async fn download_file_in_segments(
+    client: &Client,
+    url: &str,
+    file_info: &FileDownloadInfo,
+    filename: &str,
+    saved_state: Option<DownloadState>,
+    multi_progress: &MultiProgress,
+    options: &DownloadOptions,
+) -> Result<String, Error> {
+    let part_filename = part_filename(filename);
+
+    let state = match saved_state {
+        Some(state)
+            if state.matches(file_info)
+                && !state.segments.is_empty()
+                && fs::metadata(&part_filename).await.is_ok() =>
+        {
+            debug!("Resuming download of {filename} in segments: {state:?}");
+            state
+        }
+        saved_state => {
+            if saved_state.is_some_and(|state| !state.matches(file_info)) {
+                println!(
+                    "File {filename} changed on the server since its download started, downloading it again"
+                );
+            }
+            let mut state = DownloadState::new(file_info);
+            state.split(options.connections);
+            debug!("Starting download of {filename} in segments: {state:?}");
+            // Saving the state first, so that the allocated file is never mistaken for a complete download
+            save_download_state(filename, &state).await?;
+            // Allocating the whole file, segments being written at their own position
+            let file = File::create(&part_filename)
+                .await
+                .with_context(|| format!("Failed to create file {part_filename}"))?;
+            file.set_len(file_info.filesize)
+                .await
+                .with_context(|| format!("Failed to allocate file {part_filename}"))?;
+            state
+        }
+    };
+
+    let progress_bar = add_progress_bar(
+        multi_progress,
+        file_info.filesize,
+        filename,
+        state.downloaded(),
+    );
+    let segment_count = state.segments.len();
+    let download = SegmentedDownload {
+        client,
+        url,
+        filename,
+        state: Mutex::new(state),
+        progress_bar,
+        multi_progress,
+        options,
+    };
+
+    // Not stopping on first failure, so that every segment saves its progress
+    let results =
+        join_all((0..segment_count).map(|index| download_segment(&download, index))).await;
+    if results
+        .iter()
+        .any(|result| result.as_ref().is_err_and(interrupt::is_interruption))
+    {
+        download.progress_bar.abandon();
+        let downloaded = download.state.lock().await.downloaded();
+        return Err(Error::new(interrupt::Interrupted).context(format!(
+            "{} of {} downloaded to {part_filename}, progress of segments saved to {}",
+            DecimalBytes(downloaded),
+            DecimalBytes(file_info.filesize),
+            download_state_filename(filename)
+        )));
+    }
+    results.into_iter().collect::<Result<Vec<()>, Error>>()?;
+
+    download.progress_bar.finish();
+
+    complete_download(
+        filename,
+        file_info.filesize,
+        &file_info.digests,
+        multi_progress,
+    )
+    .await?;
+    Ok(filename.to_string())
+}
+
+// Download of a file in segments, shared by the tasks downloading each segment
+struct SegmentedDownload<'a> {
+    client: &'a Client,
+    url: &'a str,
+    filename: &'a str,
+    state: Mutex<DownloadState>,
+    progress_bar: ProgressBar,
+    multi_progress: &'a MultiProgress,
+    options: &'a DownloadOptions,
+}
+
+// Download the remaining part of a segment, saving its progress regularly
+async fn download_segment(download: &SegmentedDownload<'_>, index: usize) -> Result<(), Error> {
+    let client = download.client;
+    let url = download.url;
+    let filename = download.filename;
+    let state = &download.state;
+    let progress_bar = &download.progress_bar;
+    let mut retry = Retry::new(download.options, download.multi_progress);
+    let (segment, if_range) = {
+        let state = state.lock().await;
+        (
+            state.segments[index].clone(),
+            state.if_range().map(str::to_string),
+        )
+    };
+    let if_range = if_range.as_deref();
+    if segment.is_complete() {
+        return Ok(());
+    }
+
+    let part_filename = part_filename(filename);
+    let mut downloaded = segment.downloaded;
+    // Downloaded data actually written to the file, only this progress can be saved
+    let mut flushed = downloaded;
+    let result = async {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&part_filename)
+            .await
+            .with_context(|| format!("Failed to open file {part_filename} in write mode"))?;
+        file.seek(SeekFrom::Start(segment.position()))
+            .await
+            .with_context(|| format!("Failed to seek in file {part_filename}"))?;
+        let mut file_writer = BufWriter::new(file);
+
+        // Requesting the remaining part of the segment again after each transient error
+        let mut result = Ok(());
+        while result.is_ok() && segment.start + downloaded < segment.end {
+            let position = segment.start + downloaded;
+            let response = match retry
+                .run(|| async move {
+                    let response =
+                        send_download_request(client, url, position, Some(segment.end), if_range)
+                            .await?;
+                    if response.status() == StatusCode::OK && if_range.is_some() {
+                        return Err(anyhow!(
+                            "File {filename} changed on the server during its download, it will be downloaded again on next attempt"
+                        ));
+                    }
+                    if response.status() != StatusCode::PARTIAL_CONTENT {
+                        return Err(anyhow!(
+                            "Failed to download segment of file {filename}, got status {}.",
+                            response.status()
+                        ));
+                    }
+                    Ok(response)
+                })
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            let mut stream = response.bytes_stream();
+            let error = loop {
+                let next = tokio::select! {
+                    biased;
+                    _ = interrupt::wait() => break Some(Error::new(interrupt::Interrupted)),
+                    next = stream.next() => next,
+                };
+                let chunk = match next {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => {
+                        break Some(
+                            Error::new(e)
+                                .context(format!("Failed to download file {filename} from {url}")),
+                        );
+                    }
+                    None if segment.start + downloaded < segment.end => {
+                        // Requesting the rest of the segment again, from the downloaded position
+                        break Some(Error::new(TruncatedResponse {
+                            filename: filename.to_string(),
+                        }));
+                    }
+                    None => break None,
+                };
+                // Server might send more than requested
+                let length = (chunk.len() as u64).min(segment.end - segment.start - downloaded);
+                file_writer
+                    .write_all(&chunk[..length as usize])
+                    .await
+                    .with_context(|| format!("Error writing to file {part_filename}"))?;
+                downloaded += length;
+                progress_bar.inc(length);
+                retry.reset();
+                download.options.rate_limiter.consume(length).await;
+
+                if downloaded - flushed >= SEGMENT_SAVE_INTERVAL {
+                    file_writer
+                        .flush()
+                        .await
+                        .with_context(|| format!("Error flushing file {part_filename}"))?;
+                    flushed = downloaded;
+                    let mut state = state.lock().await;
+                    state.segments[index].downloaded = flushed;
+                    save_download_state(filename, &state).await?;
+                }
+            };
+            if let Some(error) = error {
+                result = retry.wait(error).await;
+            }
+        }
+        file_writer
+            .flush()
+            .await
+            .with_context(|| format!("Error flushing file {part_filename}"))?;
+        flushed = downloaded;
+        result
+    }
+    .await;
+
+    // Saving progress, whatever the result
+    let mut state = state.lock().await;
+    state.segments[index].downloaded = flushed;
+    save_download_state(filename, &state).await?;
+    result
+}
+
+// Value of the If-Range header, so that a range is only sent if the remote file did not change: the ETag if it is a
+// strong one, otherwise the Last-Modified date
+fn if_range_value<'a>(etag: Option<&'a str>, last_modified: Option<&'a str>) -> Option<&'a str> {
+    match etag {
+        Some(etag) if !etag.starts_with("W/") => Some(etag),
+        _ => last_modified,
+    }
+}
+
+// Issue a GET request to download a file, starting from the given position, up to the given end position (exclusive).
+// When resuming, the whole file is sent instead (status 200) if it does not match the If-Range validator.
+async fn send_download_request(
+    client: &Client,
+    url: &str,
+    start: u64,
+    end: Option<u64>,
+    if_range: Option<&str>,
+) -> Result<Response, Error> {
+    let mut request = client.get(url);
+    if let Some(end) = end {
+        debug!(
+            "Adding range header to download a segment: bytes={start}-{}",
+            end - 1
+        );
+        request = request.header(RANGE, format!("bytes={start}-{}", end - 1));
+    } else if start > 0 {
+        debug!("Adding range header to resume download: bytes={start}-");
+        request = request.header(RANGE, format!("bytes={start}-"));
+    }
+    if let Some(if_range) = if_range
+        && (start > 0 || end.is_some())
+    {
+        request = request.header(IF_RANGE, if_range);
+    }
+
+    debug!("Sending request GET {url}");
+    let response = request.send().await?;
+    debug!("Received response {response:?}");
+
+    check_status(response, "Failed to download file")
+}
+
+// Fail on error statuses, keeping the status in the error so that transient errors can be retried
+fn check_status(response: Response, message: &str) -> Result<Response, Error> {
+    let status = response.status();
+    response
+        .error_for_status()
+        .map_err(|e| Error::new(e).context(format!("{message}, got status {status}.")))
+}
+
+fn add_progress_bar(
+    multi_progress: &MultiProgress,
+    length: u64,
+    filename: &str,
+    position: u64,
+) -> ProgressBar {
+    let progress_bar = multi_progress.add(interact::progress_bar(length));
+    // Triggers first draw
+    progress_bar.set_message(
+        Path::new(filename)
+            .file_name()
+            .map_or(filename.into(), |name| name.to_string_lossy())
+            .to_string(),
+    );
+    progress_bar.set_position(position);
+    // Need to reset ETA in case of resume, otherwise estimations are biased
+    progress_bar.reset_eta();
+    progress_bar
+}
+
 // Parse the name of the file to download from the response
 fn parse_filename(response: &Response) -> Result<&str, Error> {
     // Try to parse content-disposition header for filename
@@ -216,3 +1117,152 @@ fn parse_filename_from_content_disposition(response: &Response) -> Result<Option
         content_disposition_str
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_without_segments(filesize: u64) -> DownloadState {
+        DownloadState {
+            filesize,
+            etag: None,
+            last_modified: None,
+            segments: Vec::new(),
+        }
+    }
+
+    fn state(filesize: u64, connections: u64) -> DownloadState {
+        let mut state = state_without_segments(filesize);
+        state.split(connections);
+        state
+    }
+
+    fn bounds(state: &DownloadState) -> Vec<(u64, u64)> {
+        state.segments.iter().map(|s| (s.start, s.end)).collect()
+    }
+
+    fn status_error(status: u16) -> Error {
+        let response = http::Response::builder().status(status).body("").unwrap();
+        check_status(Response::from(response), "Failed").unwrap_err()
+    }
+
+    #[test]
+    fn split_covers_the_whole_file() {
+        let size = 4 * MIN_SEGMENT_SIZE;
+        assert_eq!(
+            bounds(&state(size, 4)),
+            vec![
+                (0, MIN_SEGMENT_SIZE),
+                (MIN_SEGMENT_SIZE, 2 * MIN_SEGMENT_SIZE),
+                (2 * MIN_SEGMENT_SIZE, 3 * MIN_SEGMENT_SIZE),
+                (3 * MIN_SEGMENT_SIZE, size),
+            ]
+        );
+        // Last segment is the smallest when the size does not divide evenly
+        let size = 3 * MIN_SEGMENT_SIZE + 1;
+        let segments = bounds(&state(size, 3));
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0], (0, MIN_SEGMENT_SIZE + 1));
+        assert_eq!(
+            segments[1],
+            (MIN_SEGMENT_SIZE + 1, 2 * MIN_SEGMENT_SIZE + 2)
+        );
+        assert_eq!(segments[2], (2 * MIN_SEGMENT_SIZE + 2, size));
+    }
+
+    #[test]
+    fn split_limits_segments_to_minimum_size() {
+        // More connections than segments of the minimum size
+        assert_eq!(bounds(&state(2 * MIN_SEGMENT_SIZE + 5, 8)).len(), 2);
+        // Small files and even empty files are downloaded in a single segment
+        assert_eq!(bounds(&state(1000, 8)), vec![(0, 1000)]);
+        assert_eq!(bounds(&state(1, 4)), vec![(0, 1)]);
+        assert_eq!(bounds(&state(0, 4)), vec![(0, 0)]);
+        assert!(state(0, 4).segments[0].is_complete());
+    }
+
+    #[test]
+    fn segments_resume_from_their_downloaded_position() {
+        let mut state = state(3 * MIN_SEGMENT_SIZE, 3);
+        state.segments[0].downloaded = MIN_SEGMENT_SIZE;
+        state.segments[1].downloaded = 100;
+        assert!(state.segments[0].is_complete());
+        assert_eq!(state.segments[1].position(), MIN_SEGMENT_SIZE + 100);
+        assert!(!state.segments[1].is_complete());
+        assert_eq!(state.segments[2].position(), 2 * MIN_SEGMENT_SIZE);
+        assert_eq!(state.downloaded(), MIN_SEGMENT_SIZE + 100);
+
+        // Progress saved to the state file is restored as is
+        let saved: DownloadState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        let positions: Vec<_> = saved.segments.iter().map(Segment::position).collect();
+        assert_eq!(
+            positions,
+            vec![
+                MIN_SEGMENT_SIZE,
+                MIN_SEGMENT_SIZE + 100,
+                2 * MIN_SEGMENT_SIZE
+            ]
+        );
+    }
+
+    #[test]
+    fn restart_resets_size_validators_and_segments() {
+        let mut state = state(3 * MIN_SEGMENT_SIZE, 3);
+        state.etag = Some("\"old\"".to_string());
+        state.last_modified = Some("Sun, 07 Feb 2021 11:47:22 GMT".to_string());
+        state.segments[0].downloaded = 100;
+        let size = 4 * MIN_SEGMENT_SIZE + 1;
+        let response = http::Response::builder()
+            .status(200)
+            .header(ETAG, "\"new\"")
+            .body(vec![0; size as usize])
+            .unwrap();
+        state.restart(&Response::from(response));
+        assert_eq!(state.filesize, size);
+        assert_eq!(state.etag.as_deref(), Some("\"new\""));
+        assert_eq!(state.last_modified, None);
+        assert_eq!(state.downloaded(), 0);
+        assert_eq!(state.segments.len(), 3);
+        assert_eq!(state.segments.last().unwrap().end, size);
+
+        // Not downloading in segments
+        let mut state = state_without_segments(100);
+        let response = http::Response::builder().status(200).body("hello").unwrap();
+        state.restart(&Response::from(response));
+        assert_eq!(state.filesize, 5);
+        assert!(state.segments.is_empty());
+    }
+
+    #[test]
+    fn if_range_prefers_strong_etag() {
+        let date = Some("Sun, 07 Feb 2021 11:47:22 GMT");
+        assert_eq!(if_range_value(Some("\"abc\""), date), Some("\"abc\""));
+        // Weak ETags cannot be used with If-Range
+        assert_eq!(if_range_value(Some("W/\"abc\""), date), date);
+        assert_eq!(if_range_value(None, date), date);
+        assert_eq!(if_range_value(Some("W/\"abc\""), None), None);
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        assert!(is_transient(&status_error(429)));
+        assert!(is_transient(&status_error(500)));
+        assert!(is_transient(&status_error(503)));
+        assert!(is_transient(&Error::new(TruncatedResponse {
+            filename: "file".to_string()
+        })));
+        // Also when context was added
+        assert!(is_transient(
+            &status_error(502).context("Failed to download")
+        ));
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        assert!(!is_transient(&status_error(403)));
+        assert!(!is_transient(&status_error(404)));
+        assert!(!is_transient(&anyhow!("File changed on the server")));
+        assert!(!is_transient(&Error::new(interrupt::Interrupted)));
+    }
+}
@@ -0,0 +1,161 @@
+use std::path::{Component, Path};
+
+// Constraints of the FAT32 file system, that USB drives used for updates are formatted with
+
+// Characters that cannot be used in FAT32 long file names
+const RESERVED_CHARACTERS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+// Names reserved by Windows for devices, that cannot be used whatever the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Maximum length of a long file name, in UTF-16 code units
+const MAX_NAME_LENGTH: usize = 255;
+
+// Maximum length of a path relative to the root of the drive, in UTF-16 code units. Windows paths are limited to 260
+// characters, including the drive (e.g. "D:\") and the terminating null character.
+const MAX_PATH_LENGTH: usize = 256;
+
+// Maximum size of a file, its size being stored on 32 bits
+pub const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024 - 1;
+
+// Check that a file or directory name can be stored on a FAT32 file system
+// Returns the reason why it can't, if any
+pub fn check_name(name: &str) -> Option<String> {
+    if let Some(c) = name
+        .chars()
+        .find(|c| RESERVED_CHARACTERS.contains(c) || c.is_ascii_control())
+    {
+        return Some(format!(
+            "Name contains a character not allowed on FAT32: {c:?}"
+        ));
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Some("Name ends with a dot or a space, not allowed on FAT32".to_string());
+    }
+    let stem = name.split('.').next().unwrap_or(name);
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return Some(format!("Name {stem} is reserved on FAT32"));
+    }
+    if name.encode_utf16().count() > MAX_NAME_LENGTH {
+        return Some(format!(
+            "Name is longer than {MAX_NAME_LENGTH} characters, not allowed on FAT32"
+        ));
+    }
+    None
+}
+
+// Check that a path relative to the root of the drive can be stored on a FAT32 file system
+// Returns the reason why it can't, if any
+pub fn check_path(path: &Path) -> Option<String> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            let name = name.to_string_lossy();
+            if let Some(message) = check_name(&name) {
+                return Some(message);
+            }
+            names.push(name);
+        }
+    }
+    let length = names.join("\\").encode_utf16().count();
+    if length > MAX_PATH_LENGTH {
+        return Some(format!(
+            "Path is {length} characters long, longer than the {MAX_PATH_LENGTH} characters allowed on FAT32"
+        ));
+    }
+    None
+}
+
+// Check that a file is small enough to be stored on a FAT32 file system
+pub fn check_file_size(size: u64) -> Option<String> {
+    if size > MAX_FILE_SIZE {
+        return Some(format!(
+            "File is {size} bytes, larger than the {MAX_FILE_SIZE} bytes (4 GiB - 1) allowed on FAT32"
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_name_accepts_valid_names() {
+        assert_eq!(check_name("PSA_map-eur_20.0.0-r0-NAC_EUR_WAVE2.tar"), None);
+        assert_eq!(check_name("license.txt"), None);
+        assert_eq!(check_name(".hidden"), None);
+        assert_eq!(check_name("name with spaces"), None);
+        assert_eq!(check_name("été"), None);
+    }
+
+    #[test]
+    fn check_name_refuses_reserved_characters() {
+        for c in RESERVED_CHARACTERS {
+            assert!(check_name(&format!("a{c}b")).is_some(), "{c:?}");
+        }
+        assert!(check_name("a\u{1}b").is_some());
+        assert!(check_name("a\tb").is_some());
+    }
+
+    #[test]
+    fn check_name_refuses_trailing_dot_or_space() {
+        assert!(check_name("name.").is_some());
+        assert!(check_name("name ").is_some());
+        assert!(check_name("..").is_some());
+    }
+
+    #[test]
+    fn check_name_refuses_reserved_names() {
+        assert!(check_name("CON").is_some());
+        assert!(check_name("con").is_some());
+        assert!(check_name("con.txt").is_some());
+        assert!(check_name("Com1.tar.gz").is_some());
+        assert!(check_name("LPT9").is_some());
+        assert!(check_name("nul.").is_some());
+        // Only whole names are reserved
+        assert_eq!(check_name("CONSOLE"), None);
+        assert_eq!(check_name("COM10"), None);
+        assert_eq!(check_name("aux_file.txt"), None);
+        assert_eq!(check_name("file.con"), None);
+    }
+
+    #[test]
+    fn check_name_refuses_long_names() {
+        assert_eq!(check_name(&"a".repeat(MAX_NAME_LENGTH)), None);
+        assert!(check_name(&"a".repeat(MAX_NAME_LENGTH + 1)).is_some());
+        // Length in UTF-16 code units, characters outside of the BMP taking two
+        assert_eq!(check_name(&"é".repeat(MAX_NAME_LENGTH)), None);
+        assert!(check_name(&"😀".repeat(MAX_NAME_LENGTH / 2 + 1)).is_some());
+    }
+
+    #[test]
+    fn check_path_refuses_long_paths() {
+        // Path length includes separators
+        let name = "a".repeat(126);
+        assert_eq!(check_path(Path::new(&format!("{name}/{name}/ab"))), None);
+        assert!(check_path(Path::new(&format!("{name}/{name}/abc"))).is_some());
+        // Leading "./" and trailing "/" do not count
+        assert_eq!(check_path(Path::new(&format!("./{name}/{name}/ab/"))), None);
+    }
+
+    #[test]
+    fn check_path_checks_every_name() {
+        assert_eq!(check_path(Path::new("./dir/sub dir/file.txt")), None);
+        assert!(check_path(Path::new("dir/con.txt")).is_some());
+        assert!(check_path(Path::new("AUX/file.txt")).is_some());
+        assert!(check_path(Path::new("dir/a?b/file.txt")).is_some());
+        assert!(check_path(Path::new(&format!("dir/{}", "a".repeat(256)))).is_some());
+    }
+
+    #[test]
+    fn check_file_size_refuses_files_over_4_gib() {
+        assert_eq!(check_file_size(0), None);
+        assert_eq!(check_file_size(MAX_FILE_SIZE), None);
+        assert!(check_file_size(MAX_FILE_SIZE + 1).is_some());
+        assert!(check_file_size(8 * 1024 * 1024 * 1024).is_some());
+    }
+}
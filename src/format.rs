@@ -0,0 +1,649 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Error, Result, anyhow};
+
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+
+use indicatif::DecimalBytes;
+
+use log::debug;
+
+use crate::disk;
+
+// Partitioning and formatting of USB drives, or of disk images, as FAT32
+
+const MB: u64 = 1024 * 1024;
+const GB: u64 = 1024 * MB;
+
+// FAT32 requires at least 65525 clusters, this leaves some margin with the smallest cluster size
+const MIN_VOLUME_SIZE: u64 = 64 * MB;
+
+const MIN_CLUSTERS: u64 = 65525;
+
+// Number of sectors being stored on 32 bits, with 512-byte sectors
+const MAX_VOLUME_SIZE: u64 = 2048 * GB - SECTOR_SIZE;
+
+const SECTOR_SIZE: u64 = 512;
+
+// Largest partition created when preparing a USB drive, Windows refusing to format larger volumes as FAT32
+const MAX_PARTITION_SIZE: u64 = 32 * GB;
+
+// Offset of the partition created when preparing a USB drive, aligned on the erase blocks of flash memory
+pub const PARTITION_OFFSET: u64 = MB;
+
+// MBR partition type of FAT32 volumes addressed with LBA
+const FAT32_LBA_PARTITION_TYPE: u8 = 0x0C;
+
+// Space taken in its directory by the entries of a file with a long name of up to 255 characters
+const MAX_DIRECTORY_ENTRIES_SIZE: u64 = 21 * 32;
+
+// Formatted volume, as read back from the device
+pub struct Volume {
+    pub size: u64,
+    pub cluster_size: u32,
+    pub available_space: u64,
+}
+
+// Check that a device or a disk image file can be formatted. Block devices must be removable and not mounted.
+pub fn check_target(path: &Path) -> Result<(), Error> {
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("Failed to get metadata of {}", path.to_string_lossy()))?;
+    if metadata.is_file() {
+        return Ok(());
+    }
+    if is_block_device(&metadata) {
+        return disk::check_device(path);
+    }
+    Err(anyhow!(
+        "{} is neither a block device nor a disk image file",
+        path.to_string_lossy()
+    ))
+}
+
+#[cfg(unix)]
+fn is_block_device(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_block_device()
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+// Size of a device or of a disk image file, in bytes
+pub fn volume_size(path: &Path) -> Result<u64, Error> {
+    // Metadata of block devices do not have their size
+    File::open(path)
+        .and_then(|mut file| file.seek(SeekFrom::End(0)))
+        .with_context(|| format!("Failed to get size of {}", path.to_string_lossy()))
+}
+
+// Check that a volume of the given size can be formatted as FAT32
+pub fn check_size(path: &Path, size: u64) -> Result<(), Error> {
+    if size < MIN_VOLUME_SIZE {
+        return Err(anyhow!(
+            "{} is too small to be formatted as FAT32, at least {} MB required",
+            path.to_string_lossy(),
+            MIN_VOLUME_SIZE / MB
+        ));
+    }
+    if size > MAX_VOLUME_SIZE {
+        return Err(anyhow!(
+            "{} is too large to be formatted as FAT32, up to 2 TB supported",
+            path.to_string_lossy()
+        ));
+    }
+    Ok(())
+}
+
+// Cluster size used by Windows when formatting a volume as FAT32, 32 KB above 32 GB where Windows refuses to format
+fn cluster_size(volume_size: u64) -> u32 {
+    let mut cluster_size = match volume_size {
+        size if size < 64 * MB => 512,
+        size if size < 128 * MB => 1024,
+        size if size < 256 * MB => 2048,
+        size if size < 8 * GB => 4096,
+        size if size < 16 * GB => 8192,
+        size if size < 32 * GB => 16384,
+        _ => 32768,
+    };
+    // Volumes at the lower bound of a size range would have too few clusters once the FATs are allocated, e.g. 64 MB
+    // volumes with 1 KB clusters, and would be FAT16 volumes
+    while cluster_size > 512 && volume_size / u64::from(cluster_size) < MIN_CLUSTERS * 101 / 100 {
+        cluster_size /= 2;
+    }
+    cluster_size
+}
+
+// Volume label as stored in the boot sector: 11 upper case characters padded with spaces
+pub fn volume_label(label: &str) -> Result<[u8; 11], Error> {
+    let label = label.to_uppercase();
+    if label.len() > 11
+        || !label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '_' || c == '-')
+    {
+        return Err(anyhow!(
+            "Invalid volume label {label}: up to 11 letters, digits, spaces, '_' or '-' allowed"
+        ));
+    }
+    let mut bytes = [b' '; 11];
+    bytes[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(bytes)
+}
+
+// Format a device or a disk image file as FAT32, erasing all its data
+pub fn format(path: &Path, label: Option<[u8; 11]>) -> Result<Volume, Error> {
+    let filename = path.to_string_lossy();
+    let size = volume_size(path)?;
+    check_size(path, size)?;
+
+    let mut file = open(path)?;
+    format_partition(&mut file, 0, size, label)
+        .with_context(|| format!("Failed to format {filename}"))
+}
+
+// Partition a device or a disk image file with a single FAT32 partition of up to 32 GB, and format this partition,
+// erasing all data. The partition starts at PARTITION_OFFSET.
+pub fn prepare(path: &Path, label: Option<[u8; 11]>) -> Result<Volume, Error> {
+    let filename = path.to_string_lossy();
+    let size = volume_size(path)?;
+    let partition_size = partition_size(size);
+    check_size(path, partition_size)?;
+
+    let mut file = open(path)?;
+    // Erase the partition table, and the backup GPT header at the end of the disk, not to leave a stale GPT the
+    // system might prefer to the MBR
+    debug!("Erasing first and last MB of {filename}");
+    write_zeros(&mut file, 0, MB)
+        .and_then(|_| write_zeros(&mut file, size - MB, MB))
+        .with_context(|| format!("Failed to erase {filename}"))?;
+    debug!("Writing partition table of {filename}, partition of {partition_size} bytes");
+    write_mbr(&mut file, partition_size)
+        .with_context(|| format!("Failed to write partition table of {filename}"))?;
+    format_partition(&mut file, PARTITION_OFFSET, partition_size, label)
+        .with_context(|| format!("Failed to format partition of {filename}"))
+}
+
+// Size of the partition created when preparing a disk of the given size
+pub fn partition_size(disk_size: u64) -> u64 {
+    disk_size
+        .saturating_sub(PARTITION_OFFSET)
+        .min(MAX_PARTITION_SIZE)
+        / MB
+        * MB
+}
+
+// Size of a disk image file large enough to store files of files_size bytes in total once partitioned and formatted,
+// entry_count being the number of files and directories. Each of them is counted with a full cluster of slack, and
+// with directory entries for the longest name.
+pub fn image_size(files_size: u64, entry_count: u64) -> u64 {
+    let mut bytes_per_cluster = u64::from(cluster_size(files_size));
+    loop {
+        // Including the cluster of the root directory
+        let clusters = files_size.div_ceil(bytes_per_cluster)
+            + entry_count * (1 + MAX_DIRECTORY_ENTRIES_SIZE.div_ceil(bytes_per_cluster))
+            + 1;
+        // Reserved sectors, two FATs of 4 bytes per cluster, and data, with one more MB for rounding
+        let partition_size =
+            (32 * SECTOR_SIZE + 2 * 4 * (clusters + 2) + clusters * bytes_per_cluster).div_ceil(MB)
+                * MB
+                + MB;
+        let partition_size = partition_size.max(MIN_VOLUME_SIZE);
+        // Larger volumes have larger clusters, and thus more slack
+        if u64::from(cluster_size(partition_size)) == bytes_per_cluster {
+            return PARTITION_OFFSET + partition_size;
+        }
+        bytes_per_cluster = u64::from(cluster_size(partition_size));
+    }
+}
+
+// Create a disk image file of the given size, partitioned and formatted as a prepared USB drive, so that it can be
+// written to USB drives as is. Refuses to overwrite an existing file.
+pub fn create_image(path: &Path, size: u64, label: Option<[u8; 11]>) -> Result<Volume, Error> {
+    let filename = path.to_string_lossy();
+    if size - PARTITION_OFFSET > MAX_PARTITION_SIZE {
+        return Err(anyhow!(
+            "Image {filename} would be {}, larger than the 32 GB partition of a USB drive",
+            DecimalBytes(size)
+        ));
+    }
+    debug!("Creating image {filename} of {size} bytes");
+    // Sparse file, only written blocks taking space on disk
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|file| file.set_len(size))
+        .with_context(|| format!("Failed to create image {filename}"))?;
+    prepare(path, label).inspect_err(|_| {
+        if let Err(e) = fs::remove_file(path) {
+            debug!("Failed to remove image {filename}: {e}");
+        }
+    })
+}
+
+// Run f with the file system of a disk image file created by create_image, to write to it without mounting it
+pub fn with_image_file_system<T>(
+    path: &Path,
+    f: impl FnOnce(&FileSystem<Slice>) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let filename = path.to_string_lossy();
+    let size = volume_size(path)?;
+    let mut file = open(path)?;
+    let file_system = FileSystem::new(
+        Slice::new(&mut file, PARTITION_OFFSET, size - PARTITION_OFFSET)?,
+        FsOptions::new(),
+    )
+    .with_context(|| format!("Failed to open file system of image {filename}"))?;
+    let result = f(&file_system)?;
+    // Writes the count of free clusters
+    file_system
+        .unmount()
+        .with_context(|| format!("Failed to write image {filename}"))?;
+    file.sync_all()
+        .with_context(|| format!("Failed to write image {filename}"))?;
+    Ok(result)
+}
+
+fn open(path: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.to_string_lossy()))
+}
+
+// Format the part of a device starting at offset as FAT32, and read it back
+fn format_partition(
+    file: &mut File,
+    offset: u64,
+    size: u64,
+    label: Option<[u8; 11]>,
+) -> Result<Volume, Error> {
+    let mut options = FormatVolumeOptions::new()
+        .fat_type(FatType::Fat32)
+        .bytes_per_cluster(cluster_size(size))
+        .volume_id(volume_id());
+    if let Some(label) = label {
+        options = options.volume_label(label);
+    }
+
+    // Erase signatures of previous file systems (e.g. backup boot sector of exFAT) that might be left in the reserved
+    // sectors, so that the drive is not detected as such
+    write_zeros(file, offset, MB.min(size))?;
+
+    debug!(
+        "Formatting {size} bytes at offset {offset} with clusters of {} bytes",
+        cluster_size(size)
+    );
+    fatfs::format_volume(Slice::new(file, offset, size)?, options)?;
+    if offset > 0 {
+        write_hidden_sectors(file, offset)?;
+    }
+    file.sync_all()?;
+
+    read_volume(Slice::new(file, offset, size)?, size).context("Failed to read back volume")
+}
+
+fn write_zeros(file: &mut File, offset: u64, length: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&vec![0; length as usize])
+}
+
+// Write a MBR partition table with a single FAT32 partition starting at PARTITION_OFFSET
+fn write_mbr(file: &mut File, partition_size: u64) -> io::Result<()> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    // Disk signature
+    mbr[440..444].copy_from_slice(&volume_id().to_le_bytes());
+    let entry = &mut mbr[446..462];
+    // CHS addresses are not used by current systems, set to their maximum as done for partitions beyond 8 GB
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = FAT32_LBA_PARTITION_TYPE;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&((PARTITION_OFFSET / SECTOR_SIZE) as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&((partition_size / SECTOR_SIZE) as u32).to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&mbr)
+}
+
+// Record the number of sectors before the partition in its boot sector and in the backup boot sector, fatfs always
+// writing 0 as for unpartitioned devices
+fn write_hidden_sectors(file: &mut File, offset: u64) -> io::Result<()> {
+    // Backup boot sector is at sector 6 of FAT32 volumes
+    for boot_sector in [0, 6] {
+        file.seek(SeekFrom::Start(offset + boot_sector * SECTOR_SIZE + 0x1C))?;
+        file.write_all(&((offset / SECTOR_SIZE) as u32).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Read back a formatted volume, making sure it is recognized as FAT32
+fn read_volume(slice: Slice, size: u64) -> Result<Volume, Error> {
+    let file_system = FileSystem::new(slice, FsOptions::new())?;
+    if file_system.fat_type() != FatType::Fat32 {
+        return Err(anyhow!(
+            "File system is {:?} instead of FAT32",
+            file_system.fat_type()
+        ));
+    }
+    let stats = file_system.stats()?;
+    Ok(Volume {
+        size,
+        cluster_size: stats.cluster_size(),
+        available_space: u64::from(stats.free_clusters()) * u64::from(stats.cluster_size()),
+    })
+}
+
+// Volume serial number, derived from the current time as done by other formatting tools
+fn volume_id() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+// Part of a device or of a disk image file, e.g. a partition, seen by fatfs as a whole volume
+pub struct Slice<'a> {
+    file: &'a mut File,
+    offset: u64,
+    size: u64,
+    position: u64,
+}
+
+impl<'a> Slice<'a> {
+    fn new(file: &'a mut File, offset: u64, size: u64) -> io::Result<Slice<'a>> {
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Slice {
+            file,
+            offset,
+            size,
+            position: 0,
+        })
+    }
+
+    fn remaining(&self, length: usize) -> usize {
+        length.min(self.size.saturating_sub(self.position) as usize)
+    }
+}
+
+impl Read for Slice<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.remaining(buf.len());
+        let read = self.file.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Slice<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.remaining(buf.len());
+        if length == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Write beyond the end of the volume",
+            ));
+        }
+        let written = self.file.write(&buf[..length])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Slice<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position"))?;
+        self.file.seek(SeekFrom::Start(self.offset + position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sparse disk image file of the given size, removed when dropped
+    fn image(size: u64) -> tempfile::NamedTempFile {
+        let image = tempfile::NamedTempFile::new().unwrap();
+        image.as_file().set_len(size).unwrap();
+        image
+    }
+
+    fn read_sector(file: &mut File, position: u64) -> [u8; SECTOR_SIZE as usize] {
+        let mut sector = [0; SECTOR_SIZE as usize];
+        file.seek(SeekFrom::Start(position)).unwrap();
+        file.read_exact(&mut sector).unwrap();
+        sector
+    }
+
+    fn le_u32(bytes: &[u8]) -> u32 {
+        u32::from_le_bytes(bytes[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn cluster_size_follows_windows_defaults() {
+        assert_eq!(cluster_size(100 * MB), 1024);
+        assert_eq!(cluster_size(128 * MB - 1), 1024);
+        assert_eq!(cluster_size(200 * MB), 2048);
+        assert_eq!(cluster_size(256 * MB - 1), 2048);
+        assert_eq!(cluster_size(512 * MB), 4096);
+        assert_eq!(cluster_size(8 * GB - 1), 4096);
+        assert_eq!(cluster_size(8 * GB), 8192);
+        assert_eq!(cluster_size(16 * GB - 1), 8192);
+        assert_eq!(cluster_size(16 * GB), 16384);
+        assert_eq!(cluster_size(32 * GB - 1), 16384);
+        assert_eq!(cluster_size(32 * GB), 32768);
+        assert_eq!(cluster_size(MAX_VOLUME_SIZE), 32768);
+    }
+
+    #[test]
+    fn cluster_size_leaves_enough_clusters_for_fat32() {
+        // Lower bounds of the ranges of the table, with half the clusters required by FAT32
+        assert_eq!(cluster_size(64 * MB), 512);
+        assert_eq!(cluster_size(128 * MB), 1024);
+        assert_eq!(cluster_size(256 * MB), 2048);
+        for size in [
+            MIN_VOLUME_SIZE,
+            100 * MB,
+            128 * MB,
+            256 * MB,
+            8 * GB,
+            16 * GB,
+            32 * GB,
+        ] {
+            let clusters = size / u64::from(cluster_size(size));
+            assert!(clusters >= MIN_CLUSTERS * 101 / 100, "{size}: {clusters}");
+        }
+    }
+
+    #[test]
+    fn check_size_refuses_too_small_and_too_large_volumes() {
+        let path = Path::new("image");
+        assert!(check_size(path, MIN_VOLUME_SIZE - 1).is_err());
+        assert!(check_size(path, MIN_VOLUME_SIZE).is_ok());
+        assert!(check_size(path, MAX_VOLUME_SIZE).is_ok());
+        assert!(check_size(path, MAX_VOLUME_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn volume_label_is_padded_upper_case() {
+        assert_eq!(&volume_label("psa").unwrap(), b"PSA        ");
+        assert_eq!(&volume_label("Update_2024").unwrap(), b"UPDATE_2024");
+        assert!(volume_label("longer than 11").is_err());
+        assert!(volume_label("a.b").is_err());
+        assert!(volume_label("été").is_err());
+    }
+
+    #[test]
+    fn partition_size_is_rounded_and_limited() {
+        assert_eq!(partition_size(0), 0);
+        assert_eq!(partition_size(MB + 64 * MB), 64 * MB);
+        assert_eq!(partition_size(MB + 64 * MB + MB - 1), 64 * MB);
+        assert_eq!(partition_size(16 * GB), 16 * GB - MB);
+        assert_eq!(partition_size(MB + MAX_PARTITION_SIZE), MAX_PARTITION_SIZE);
+        assert_eq!(partition_size(64 * GB), MAX_PARTITION_SIZE);
+    }
+
+    #[test]
+    fn image_size_has_a_minimum() {
+        assert_eq!(image_size(0, 0), PARTITION_OFFSET + MIN_VOLUME_SIZE);
+        assert_eq!(image_size(MB, 2), PARTITION_OFFSET + MIN_VOLUME_SIZE);
+    }
+
+    #[test]
+    fn image_size_is_large_enough_for_files() {
+        for (files_size, entry_count) in [
+            (60 * MB, 1),
+            (64 * MB, 1000),
+            (1000 * MB, 3),
+            (10 * GB, 10),
+            (20 * GB, 100_000),
+        ] {
+            let size = image_size(files_size, entry_count);
+            let partition_size = size - PARTITION_OFFSET;
+            assert_eq!(size % MB, 0);
+            // Partition made of clusters of the size used when formatting it
+            let bytes_per_cluster = u64::from(cluster_size(partition_size));
+            let data_size = files_size.div_ceil(bytes_per_cluster) * bytes_per_cluster
+                + entry_count * bytes_per_cluster;
+            let fat_size = 2 * 4 * (partition_size / bytes_per_cluster);
+            assert!(
+                partition_size >= data_size + fat_size,
+                "{files_size} bytes in {entry_count} entries: {size}"
+            );
+        }
+    }
+
+    #[test]
+    fn image_of_image_size_stores_files() {
+        // Files of one byte more than a whole number of clusters, filling more than the smallest image
+        let file_size = 8 * MB + 1;
+        let directory_count = 4;
+        let file_count = 12;
+        let size = image_size(file_size * file_count, file_count + directory_count);
+        assert!(size > PARTITION_OFFSET + MIN_VOLUME_SIZE);
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("update.img");
+        create_image(&path, size, None).unwrap();
+
+        let content = vec![1; file_size as usize];
+        with_image_file_system(&path, |file_system| {
+            let root = file_system.root_dir();
+            for d in 0..directory_count {
+                let dir = root.create_dir(&format!("directory {d}"))?;
+                for f in 0..file_count / directory_count {
+                    let mut file = dir.create_file(&format!("{}{f}", "a".repeat(250)))?;
+                    file.write_all(&content)?;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn format_creates_fat32_volume() {
+        let image = image(100 * MB);
+        let volume = format(image.path(), Some(volume_label("psa update").unwrap())).unwrap();
+        assert_eq!(volume.size, 100 * MB);
+        assert_eq!(volume.cluster_size, 1024);
+
+        let file_system = FileSystem::new(image.reopen().unwrap(), FsOptions::new()).unwrap();
+        assert_eq!(file_system.fat_type(), FatType::Fat32);
+        assert_eq!(file_system.volume_label(), "PSA UPDATE");
+        assert_eq!(file_system.stats().unwrap().cluster_size(), 1024);
+        assert_eq!(
+            u64::from(file_system.stats().unwrap().free_clusters()) * 1024,
+            volume.available_space
+        );
+    }
+
+    #[test]
+    fn format_refuses_too_small_image() {
+        let image = image(MIN_VOLUME_SIZE - MB);
+        assert!(format(image.path(), None).is_err());
+    }
+
+    #[test]
+    fn prepare_creates_partition() {
+        let image = image(200 * MB);
+        let volume = prepare(image.path(), Some(volume_label("PSA").unwrap())).unwrap();
+        assert_eq!(volume.size, 199 * MB);
+        assert_eq!(volume.cluster_size, 2048);
+
+        let mut file = image.reopen().unwrap();
+        let mbr = read_sector(&mut file, 0);
+        assert_eq!(mbr[510..512], [0x55, 0xAA]);
+        let entry = &mbr[446..462];
+        assert_eq!(entry[4], FAT32_LBA_PARTITION_TYPE);
+        assert_eq!(le_u32(&entry[8..12]), 2048);
+        assert_eq!(le_u32(&entry[12..16]) as u64, 199 * MB / SECTOR_SIZE);
+        // Other partition entries are empty
+        assert!(mbr[462..510].iter().all(|&b| b == 0));
+        // Hidden sectors of the boot sector and of its backup
+        for boot_sector in [0, 6] {
+            let sector = read_sector(&mut file, PARTITION_OFFSET + boot_sector * SECTOR_SIZE);
+            assert_eq!(le_u32(&sector[0x1C..]), 2048);
+        }
+
+        let file_system = FileSystem::new(
+            Slice::new(&mut file, PARTITION_OFFSET, 199 * MB).unwrap(),
+            FsOptions::new(),
+        )
+        .unwrap();
+        assert_eq!(file_system.fat_type(), FatType::Fat32);
+        assert_eq!(file_system.volume_label(), "PSA");
+        assert_eq!(file_system.stats().unwrap().cluster_size(), 2048);
+    }
+
+    #[test]
+    fn prepare_limits_partition_to_32_gb() {
+        let image = image(40 * GB);
+        let volume = prepare(image.path(), None).unwrap();
+        assert_eq!(volume.size, MAX_PARTITION_SIZE);
+        assert_eq!(volume.cluster_size, 32768);
+
+        let mut file = image.reopen().unwrap();
+        let mbr = read_sector(&mut file, 0);
+        assert_eq!(
+            le_u32(&mbr[446 + 12..]) as u64,
+            MAX_PARTITION_SIZE / SECTOR_SIZE
+        );
+    }
+
+    #[test]
+    fn create_image_refuses_existing_file_and_large_images() {
+        let image = image(100 * MB);
+        assert!(create_image(image.path(), 100 * MB, None).is_err());
+        // Left untouched
+        assert!(
+            read_sector(&mut image.reopen().unwrap(), 0)
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("large.img");
+        assert!(create_image(&path, PARTITION_OFFSET + MAX_PARTITION_SIZE + MB, None).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::Error;
+
+use log::debug;
+
+use tokio::sync::Notify;
+
+use crate::interact;
+
+// Handling of Ctrl-C (SIGINT) and SIGTERM. While downloading or extracting, an interruption is recorded so that these
+// operations stop in a state they can be resumed from. Otherwise, the process exits right away as it would by default.
+
+// Exit code of a process interrupted by SIGINT, by convention 128 + signal number
+pub const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+// Number of operations in progress that stop cleanly when interrupted
+static GUARDS: AtomicUsize = AtomicUsize::new(0);
+static NOTIFY: Notify = Notify::const_new();
+
+// Error returned by operations stopped after an interruption
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted by user")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+// Operation that stops cleanly when interrupted, as long as the guard is alive
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        GUARDS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn guard() -> Guard {
+    GUARDS.fetch_add(1, Ordering::SeqCst);
+    Guard
+}
+
+// Start listening to interruptions. Must be called from within the tokio runtime.
+pub fn listen() {
+    tokio::spawn(async {
+        loop {
+            if let Err(e) = signal().await {
+                debug!("Failed to listen to interruptions: {e}");
+                return;
+            }
+            if INTERRUPTED.swap(true, Ordering::SeqCst) || GUARDS.load(Ordering::SeqCst) == 0 {
+                std::process::exit(EXIT_CODE);
+            }
+            println!();
+            interact::warn(
+                "Interrupted, stopping and saving progress... Press Ctrl-C again to exit immediately",
+            );
+            NOTIFY.notify_waiters();
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Completes once interrupted, immediately if already interrupted
+pub async fn wait() {
+    let notified = NOTIFY.notified();
+    tokio::pin!(notified);
+    // Registering before checking the flag, not to miss a notification sent in between
+    notified.as_mut().enable();
+    if is_interrupted() {
+        return;
+    }
+    notified.await;
+}
+
+// Whether an error was caused by an interruption
+pub fn is_interruption(error: &Error) -> bool {
+    error.downcast_ref::<Interrupted>().is_some()
+}
@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Error, Result, anyhow};
+
+use serde::{Deserialize, Serialize};
+
+use log::debug;
+
+use indicatif::ProgressBar;
+
+use crate::checksum;
+
+// Journal of the extraction of an archive, recording the files completely written to the destination, so that a
+// failed or interrupted extraction resumes after them. Stored next to the archive (e.g. update.tar.extract) rather
+// than on the USB drive, one JSON line per file, appended as soon as the file is written.
+pub struct ExtractionJournal {
+    filename: String,
+    file: File,
+    // Files extracted by previous attempts, by path in the archive
+    extracted: HashMap<String, JournalEntry>,
+    // Whether the hash of files extracted by previous attempts is checked before skipping them
+    verify_existing: bool,
+}
+
+// First line of the journal, the journal being discarded when the archive or the destination changed
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct JournalHeader {
+    destination: String,
+    archive_size: u64,
+    archive_modified: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+impl JournalHeader {
+    fn new(archive_filename: &str, destination: &Path) -> Result<JournalHeader, Error> {
+        let metadata = fs::metadata(archive_filename)
+            .with_context(|| format!("Failed to get metadata of file {archive_filename}"))?;
+        Ok(JournalHeader {
+            destination: fs::canonicalize(destination)
+                .unwrap_or_else(|_| destination.to_path_buf())
+                .to_string_lossy()
+                .to_string(),
+            archive_size: metadata.len(),
+            archive_modified: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+        })
+    }
+}
+
+// Size of the files extracted by previous attempts to extract an archive to destination, if any
+pub fn extracted_size(archive_filename: &str, destination: &Path) -> Option<u64> {
+    let header = JournalHeader::new(archive_filename, destination).ok()?;
+    let extracted = read_journal(&journal_filename(archive_filename), &header).ok()?;
+    Some(extracted.values().map(|entry| entry.size).sum())
+}
+
+fn journal_filename(archive_filename: &str) -> String {
+    format!("{archive_filename}.extract")
+}
+
+impl ExtractionJournal {
+    // Open the journal of the extraction of an archive to destination, starting a new one when there is no journal
+    // of a previous attempt to extract this archive to this destination
+    pub fn open(
+        archive_filename: &str,
+        destination: &Path,
+        verify_existing: bool,
+    ) -> Result<ExtractionJournal, Error> {
+        let filename = journal_filename(archive_filename);
+        let header = JournalHeader::new(archive_filename, destination)?;
+
+        let extracted = match read_journal(&filename, &header) {
+            Ok(extracted) => extracted,
+            Err(e) => {
+                debug!("Starting a new extraction journal {filename}: {e:#}");
+                HashMap::new()
+            }
+        };
+        let file = if extracted.is_empty() {
+            let mut file = File::create(&filename)
+                .with_context(|| format!("Failed to create extraction journal {filename}"))?;
+            let line =
+                serde_json::to_string(&header).context("Failed to serialize journal header")?;
+            writeln!(file, "{line}")
+                .with_context(|| format!("Failed to write extraction journal {filename}"))?;
+            file
+        } else {
+            debug!(
+                "Resuming extraction with journal {filename}: {} files already extracted",
+                extracted.len()
+            );
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(&filename)
+                .with_context(|| format!("Failed to open extraction journal {filename}"))?;
+            // Last line is incomplete when interrupted while writing it, terminating it so that the next entry is
+            // written on its own line
+            let mut last = [0];
+            file.seek(SeekFrom::End(-1))
+                .and_then(|_| file.read_exact(&mut last))
+                .with_context(|| format!("Failed to read extraction journal {filename}"))?;
+            if last[0] != b'\n' {
+                writeln!(file)
+                    .with_context(|| format!("Failed to write extraction journal {filename}"))?;
+            }
+            file
+        };
+        Ok(ExtractionJournal {
+            filename,
+            file,
+            extracted,
+            verify_existing,
+        })
+    }
+
+    // Number of files extracted by previous attempts
+    pub fn extracted_count(&self) -> usize {
+        self.extracted.len()
+    }
+
+    // SHA-256 of a file completely extracted by a previous attempt, provided it is still present in the destination
+    pub fn extracted_sha256(
+        &self,
+        path: &str,
+        size: u64,
+        destination_path: &Path,
+    ) -> Option<String> {
+        let entry = self.extracted.get(path)?;
+        if entry.size != size
+            || !fs::metadata(destination_path).is_ok_and(|metadata| metadata.len() == size)
+        {
+            debug!("File {path} was extracted but changed since, extracting it again");
+            return None;
+        }
+        if self.verify_existing {
+            let destination_filename = destination_path.to_string_lossy();
+            match checksum::hash_file(&destination_filename, &ProgressBar::hidden()) {
+                Ok(hashes) if checksum::to_hex(&hashes.sha256) == entry.sha256 => {}
+                Ok(_) => {
+                    debug!("File {destination_filename} is corrupted, extracting it again");
+                    return None;
+                }
+                Err(e) => {
+                    debug!(
+                        "Failed to verify file {destination_filename}, extracting it again: {e:#}"
+                    );
+                    return None;
+                }
+            }
+        }
+        Some(entry.sha256.clone())
+    }
+
+    // Record a file completely written to the destination
+    pub fn record(&mut self, path: &str, size: u64, sha256: &str) -> Result<(), Error> {
+        let entry = JournalEntry {
+            path: path.to_string(),
+            size,
+            sha256: sha256.to_string(),
+        };
+        let line = serde_json::to_string(&entry).context("Failed to serialize journal entry")?;
+        writeln!(self.file, "{line}")
+            .with_context(|| format!("Failed to write extraction journal {}", self.filename))
+    }
+
+    // Remove the journal once the extraction is complete
+    pub fn remove(self) -> Result<(), Error> {
+        debug!("Removing extraction journal {}", self.filename);
+        match fs::remove_file(&self.filename) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::new(e).context(format!(
+                "Failed to remove extraction journal {}",
+                self.filename
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Read the files recorded in a journal, provided it is the journal of the same extraction
+fn read_journal(
+    filename: &str,
+    header: &JournalHeader,
+) -> Result<HashMap<String, JournalEntry>, Error> {
+    let file = File::open(filename).context("No journal")?;
+    let mut lines = BufReader::new(file).lines();
+    let first_line = lines.next().context("Empty journal")??;
+    let journal_header: JournalHeader =
+        serde_json::from_str(&first_line).context("Invalid journal header")?;
+    if journal_header != *header {
+        return Err(anyhow!("Journal of another extraction: {journal_header:?}"));
+    }
+    let mut extracted = HashMap::new();
+    for line in lines {
+        // Last line might be incomplete if interrupted while writing it
+        match serde_json::from_str::<JournalEntry>(&line?) {
+            Ok(entry) => {
+                extracted.insert(entry.path.clone(), entry);
+            }
+            Err(e) => debug!("Ignoring invalid journal entry: {e}"),
+        }
+    }
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use tempfile::TempDir;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    // Archive and destination of an extraction
+    struct Extraction {
+        directory: TempDir,
+        archive: String,
+    }
+
+    impl Extraction {
+        fn new() -> Extraction {
+            let directory = tempfile::tempdir().unwrap();
+            let archive = directory.path().join("update.tar");
+            fs::write(&archive, [0; 1024]).unwrap();
+            fs::create_dir(directory.path().join("destination")).unwrap();
+            Extraction {
+                archive: archive.to_string_lossy().to_string(),
+                directory,
+            }
+        }
+
+        fn destination(&self) -> PathBuf {
+            self.directory.path().join("destination")
+        }
+
+        fn open(&self, verify_existing: bool) -> ExtractionJournal {
+            ExtractionJournal::open(&self.archive, &self.destination(), verify_existing).unwrap()
+        }
+
+        // Extract a file with the given content, recording it in the journal
+        fn extract(&self, journal: &mut ExtractionJournal, path: &str, content: &str) {
+            fs::write(self.destination().join(path), content).unwrap();
+            journal
+                .record(path, content.len() as u64, HELLO_SHA256)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn journal_is_resumed_for_same_extraction() {
+        let extraction = Extraction::new();
+        let mut journal = extraction.open(false);
+        assert_eq!(journal.extracted_count(), 0);
+        extraction.extract(&mut journal, "a.txt", "hello");
+        extraction.extract(&mut journal, "b.txt", "hello");
+        drop(journal);
+
+        assert_eq!(extraction.open(false).extracted_count(), 2);
+        assert_eq!(
+            extracted_size(&extraction.archive, &extraction.destination()),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn journal_is_ignored_when_destination_changed() {
+        let extraction = Extraction::new();
+        let mut journal = extraction.open(false);
+        extraction.extract(&mut journal, "a.txt", "hello");
+        drop(journal);
+
+        let other_destination = extraction.directory.path().join("other");
+        fs::create_dir(&other_destination).unwrap();
+        let journal = ExtractionJournal::open(&extraction.archive, &other_destination, false);
+        assert_eq!(journal.unwrap().extracted_count(), 0);
+    }
+
+    #[test]
+    fn journal_is_ignored_when_archive_size_changed() {
+        let extraction = Extraction::new();
+        let mut journal = extraction.open(false);
+        extraction.extract(&mut journal, "a.txt", "hello");
+        drop(journal);
+
+        fs::write(&extraction.archive, [0; 2048]).unwrap();
+        assert_eq!(extraction.open(false).extracted_count(), 0);
+    }
+
+    #[test]
+    fn journal_is_ignored_when_archive_modified() {
+        let extraction = Extraction::new();
+        let mut journal = extraction.open(false);
+        extraction.extract(&mut journal, "a.txt", "hello");
+        drop(journal);
+
+        let archive = File::options()
+            .write(true)
+            .open(&extraction.archive)
+            .unwrap();
+        archive
+            .set_modified(SystemTime::now() + Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(extraction.open(false).extracted_count(), 0);
+    }
+
+    #[test]
+    fn files_of_expected_size_are_skipped() {
+        let extraction = Extraction::new();
+        let mut journal = extraction.open(false);
+        extraction.extract(&mut journal, "a.txt", "hello");
+        extraction.extract(&mut journal, "b.txt", "hello");
+        extraction.extract(&mut journal, "c.txt", "hello");
+        drop(journal);
+        fs::write(extraction.destination().join("b.txt"), "hello world").unwrap();
+        fs::remove_file(extraction.destination().join("c.txt")).unwrap();
+
+        let journal = extraction.open(false);
+        let destination = extraction.destination();
+        assert_eq!(
+            journal.extracted_sha256("a.txt", 5, &destination.join("a.txt")),
+            Some(HELLO_SHA256.to_string())
+        );
+        // Size in the archive differs from the recorded one
+        assert_eq!(
+            journal.extracted_sha256("a.txt", 6, &destination.join("a.txt")),
+            None
+        );
+        // File changed or removed since
+        assert_eq!(
+            journal.extracted_sha256("b.txt", 5, &destination.join("b.txt")),
+            None
+        );
+        assert_eq!(
+            journal.extracted_sha256("c.txt", 5, &destination.join("c.txt")),
+            None
+        );
+        assert_eq!(
+            journal.extracted_sha256("d.txt", 5, &destination.join("d.txt")),
+            None
+        );
+    }
+
+    #[test]
+    fn corrupted_files_are_extracted_again_when_verifying() {
+        let extraction = Extraction::new();
+        let mut journal = extraction.open(false);
+        extraction.extract(&mut journal, "a.txt", "hello");
+        extraction.extract(&mut journal, "b.txt", "hello");
+        drop(journal);
+        // Same size, different content
+        fs::write(extraction.destination().join("b.txt"), "jello").unwrap();
+
+        let destination = extraction.destination();
+        let journal = extraction.open(true);
+        assert_eq!(
+            journal.extracted_sha256("a.txt", 5, &destination.join("a.txt")),
+            Some(HELLO_SHA256.to_string())
+        );
+        assert_eq!(
+            journal.extracted_sha256("b.txt", 5, &destination.join("b.txt")),
+            None
+        );
+        // Not detected without verifying
+        let journal = extraction.open(false);
+        assert!(
+            journal
+                .extracted_sha256("b.txt", 5, &destination.join("b.txt"))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn truncated_last_line_is_ignored() {
+        let extraction = Extraction::new();
+        let mut journal = extraction.open(false);
+        extraction.extract(&mut journal, "a.txt", "hello");
+        drop(journal);
+        // Interrupted while writing an entry
+        let journal_filename = journal_filename(&extraction.archive);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&journal_filename)
+            .unwrap();
+        write!(file, "{{\"path\":\"b.txt\",\"si").unwrap();
+        drop(file);
+
+        let mut journal = extraction.open(false);
+        assert_eq!(journal.extracted_count(), 1);
+        extraction.extract(&mut journal, "c.txt", "hello");
+        drop(journal);
+
+        // Entry written after the truncated line is read back
+        let journal = extraction.open(false);
+        assert_eq!(journal.extracted_count(), 2);
+        let destination = extraction.destination();
+        assert!(
+            journal
+                .extracted_sha256("c.txt", 5, &destination.join("c.txt"))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn journal_is_removed_once_complete() {
+        let extraction = Extraction::new();
+        let journal = extraction.open(false);
+        journal.remove().unwrap();
+        assert!(!Path::new(&journal_filename(&extraction.archive)).exists());
+        assert_eq!(
+            extracted_size(&extraction.archive, &extraction.destination()),
+            None
+        );
+    }
+}
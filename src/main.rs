@@ -1,13 +1,16 @@
-use std::path::Path;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::vec::Vec;
 
-use futures_util::future::try_join_all;
+use futures_util::{FutureExt, StreamExt, stream};
 
 use anyhow::{Context, Error, Result, anyhow};
 
-use clap::{Arg, ArgAction, Command, crate_version};
+use clap::{Arg, ArgAction, ArgMatches, Command, crate_version};
 
-use console::style;
+use console::{Style, style};
 
 use log::debug;
 
@@ -15,36 +18,183 @@ use reqwest::Client;
 
 use indicatif::{DecimalBytes, MultiProgress};
 
+mod archive;
+mod cache;
+mod checksum;
 mod disk;
 mod download;
+mod fat32;
+mod format;
 mod interact;
+mod interrupt;
+mod journal;
 mod psa;
+mod report;
+mod throttle;
+mod units;
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    env_logger::init();
+fn vin_arg() -> Arg {
+    Arg::new("VIN")
+        .help("Vehicle Identification Number (VIN) to check for update")
+        .index(1)
+}
 
+fn map_arg() -> Arg {
     let mut map_info = "Sets the map to check for update. Supported maps:".to_string();
     for map in psa::MAPS {
         map_info = format!("{}\n - {}: {}", map_info, map.get_code(), map.get_name());
     }
+    Arg::new("map")
+        .help(map_info)
+        .required(false)
+        .long("map")
+        .action(ArgAction::Set)
+}
+
+fn sequential_download_arg() -> Arg {
+    Arg::new("sequential-download")
+        .help("Forces sequential download of updates, same as --jobs 1. By default updates are downloaded concurrently.")
+        .required(false)
+        .long("sequential-download")
+        .action(ArgAction::SetTrue)
+}
+
+fn jobs_arg() -> Arg {
+    Arg::new("jobs")
+        .help("Maximum number of updates downloaded at the same time. By default all updates are downloaded concurrently.")
+        .required(false)
+        .long("jobs")
+        .value_parser(clap::value_parser!(u64).range(1..))
+        .conflicts_with("sequential-download")
+        .action(ArgAction::Set)
+}
+
+fn connections_arg() -> Arg {
+    Arg::new("connections")
+        .help("Number of connections used to download each update, in segments. Segments are resumed independently.")
+        .required(false)
+        .long("connections")
+        .value_parser(clap::value_parser!(u64).range(1..=16))
+        .default_value("1")
+        .action(ArgAction::Set)
+}
+
+fn download_dir_arg() -> Arg {
+    Arg::new("download-dir")
+        .help("Directory where updates are downloaded, in a subdirectory per update. Defaults to the user cache directory (e.g. ~/.cache/psa-update), so that updates are reused whatever the current directory and the VIN.")
+        .required(false)
+        .long("download-dir")
+        .action(ArgAction::Set)
+}
+
+fn limit_rate_arg() -> Arg {
+    Arg::new("limit-rate")
+        .help("Maximum download rate of all downloads together, in bytes per second, e.g. 500K or 5M. Can be changed while downloading by writing a rate to the limit-rate file in the download directory.")
+        .required(false)
+        .long("limit-rate")
+        .value_parser(throttle::parse_rate)
+        .action(ArgAction::Set)
+}
+
+fn retries_arg() -> Arg {
+    Arg::new("retries")
+        .help("Number of consecutive retries of a download after a network error or a temporary server error (429, 5xx)")
+        .required(false)
+        .long("retries")
+        .value_parser(clap::value_parser!(u32))
+        .default_value("5")
+        .action(ArgAction::Set)
+}
+
+fn retry_delay_arg() -> Arg {
+    Arg::new("retry-delay")
+        .help("Delay in seconds before the first retry of a download, doubled on each consecutive retry (up to 60 seconds)")
+        .required(false)
+        .long("retry-delay")
+        .value_parser(clap::value_parser!(u64))
+        .default_value("1")
+        .action(ArgAction::Set)
+}
+
+fn stream_to_arg() -> Arg {
+    Arg::new("stream-to")
+        .help("Full path to location where to extract the update files while they are downloaded, without storing them locally (IMPORTANT: Should be the root of an EMPTY USB device formatted as FAT32). Downloads cannot be resumed in this mode.")
+        .required(false)
+        .long("stream-to")
+        .action(ArgAction::Set)
+}
+
+fn no_verify_arg() -> Arg {
+    Arg::new("no-verify")
+        .help("Skips reading back the extracted files from the USB drive to compare them with the update")
+        .required(false)
+        .long("no-verify")
+        .action(ArgAction::SetTrue)
+}
+
+fn eject_arg() -> Arg {
+    Arg::new("eject")
+        .help("Unmounts and powers off the USB drive once the update is written to it, so that it can be unplugged (Linux only)")
+        .required(false)
+        .long("eject")
+        .action(ArgAction::SetTrue)
+}
 
-    let matches = Command::new("PSA firmware update.")
+fn force_arg() -> Arg {
+    Arg::new("force")
+        .help("Extracts even if the destination is not suitable: not enough space, not FAT32, not removable or not empty")
+        .required(false)
+        .long("force")
+        .action(ArgAction::SetTrue)
+}
+
+fn prepare_usb_arg() -> Arg {
+    Arg::new("prepare-usb")
+        .help("Block device of the USB drive (e.g. /dev/sdb) to partition with a single FAT32 partition of up to 32 GB, and to extract the updates to, all data being erased (Linux only). A disk image file can also be used for testing.")
+        .required(false)
+        .long("prepare-usb")
+        .action(ArgAction::Set)
+}
+
+fn label_arg() -> Arg {
+    Arg::new("label")
+        .help("Volume label, up to 11 letters, digits, spaces, '_' or '-'")
+        .required(false)
+        .long("label")
+        .action(ArgAction::Set)
+}
+
+fn confirm_arg() -> Arg {
+    Arg::new("confirm")
+        .help(
+            "Device to format, typed again to confirm without prompting (required in silent mode)",
+        )
+        .required(false)
+        .long("confirm")
+        .action(ArgAction::Set)
+}
+
+fn output_arg() -> Arg {
+    Arg::new("output")
+        .help("Sets the output format. The json format is a versioned document described in the README.")
+        .required(false)
+        .long("output")
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .action(ArgAction::Set)
+}
+
+fn cli() -> Command {
+    Command::new("PSA firmware update.")
         .version(crate_version!())
         .about("CLI alternative to Peugeot/Citroën/Opel/DS update applications for car infotainment system (NAC/RCC firmware and navigation maps), hopefully more robust. Supports for resume of downloads.")
-        .arg(Arg::new("VIN")
-            .help("Vehicle Identification Number (VIN) to check for update")
-            .required(false)
-            .index(1))
-        .arg(Arg::new("map")
-            .help(map_info)
-            .required(false)
-            .long("map")
-            .action(ArgAction::Set))
+        .arg(vin_arg().required(false))
+        .arg(map_arg())
         .arg(Arg::new("silent")
             .help("Sets silent (non-interactive) mode")
             .required(false)
             .long("silent")
+            .global(true)
             .action(ArgAction::SetTrue))
         .arg(Arg::new("download")
             .help("Automatically proceed with download of updates. Previous downloads will be resumed.")
@@ -52,24 +202,244 @@ async fn main() -> Result<(), Error> {
             .long("download")
             .action(ArgAction::SetTrue))
         .arg(Arg::new("extract")
-            .help("Full path to location where to extract the update files (IMPORTANT: Should be the root of an EMPTY USB device formatted as FAT32)")
+            .help("Full path to location where to extract the update files (IMPORTANT: Should be the root of an EMPTY USB device formatted as FAT32), or auto to select the only removable, empty FAT32 disk with enough space")
             .required(false)
             .long("extract")
             .action(ArgAction::Set))
-        .arg(Arg::new("sequential-download")
-            .help("Forces sequential download of updates. By default updates are downloaded concurrently.")
+        .arg(Arg::new("usb-label")
+            .help("With --extract auto, only selects a disk with this label")
             .required(false)
-            .long("sequential-download")
-            .action(ArgAction::SetTrue))
-        .get_matches();
+            .long("usb-label")
+            .requires("extract")
+            .action(ArgAction::Set))
+        .arg(Arg::new("usb-min-size")
+            .help("With --extract auto, only selects a disk of at least this size, e.g. 16G")
+            .required(false)
+            .long("usb-min-size")
+            .requires("extract")
+            .value_parser(disk::parse_size)
+            .action(ArgAction::Set))
+        .arg(Arg::new("usb-max-size")
+            .help("With --extract auto, only selects a disk of at most this size, e.g. 64G")
+            .required(false)
+            .long("usb-max-size")
+            .requires("extract")
+            .value_parser(disk::parse_size)
+            .action(ArgAction::Set))
+        .arg(sequential_download_arg())
+        .arg(jobs_arg())
+        .arg(download_dir_arg())
+        .arg(connections_arg())
+        .arg(limit_rate_arg())
+        .arg(retries_arg())
+        .arg(retry_delay_arg())
+        .arg(no_verify_arg())
+        .arg(eject_arg())
+        .arg(force_arg())
+        .arg(stream_to_arg().conflicts_with("extract"))
+        .arg(prepare_usb_arg().conflicts_with_all(["extract", "stream-to"]))
+        .arg(label_arg().requires("prepare-usb"))
+        .arg(confirm_arg().requires("prepare-usb"))
+        .subcommand(Command::new("check")
+            .about("Checks for available updates")
+            .arg(vin_arg().required(true))
+            .arg(map_arg())
+            .arg(output_arg()))
+        .subcommand(Command::new("download")
+            .about("Checks for available updates and downloads them to the download directory. Previous downloads will be resumed.")
+            .arg(vin_arg().required(true))
+            .arg(map_arg())
+            .arg(sequential_download_arg())
+            .arg(jobs_arg())
+            .arg(download_dir_arg())
+            .arg(connections_arg())
+            .arg(limit_rate_arg())
+            .arg(retries_arg())
+            .arg(retry_delay_arg())
+            .arg(stream_to_arg())
+            .arg(eject_arg().requires("stream-to"))
+            .arg(force_arg().requires("stream-to")))
+        .subcommand(Command::new("extract")
+            .about("Extracts a downloaded update to a USB drive")
+            .arg(Arg::new("TAR")
+                .help("Update file (tar) to extract")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("DESTINATION")
+                .help("Full path to location where to extract the update files (IMPORTANT: Should be the root of an EMPTY USB device formatted as FAT32)")
+                .required_unless_present("image")
+                .index(2))
+            .arg(Arg::new("image")
+                .help("Disk image file to create, formatted as FAT32 and sized to fit the update, to extract the update files to rather than to a USB drive. The image can then be written to USB drives, e.g. with dd or Etcher.")
+                .required(false)
+                .long("image")
+                .conflicts_with_all(["DESTINATION", "verify-existing", "eject", "force"])
+                .action(ArgAction::Set))
+            .arg(label_arg().requires("image"))
+            .arg(Arg::new("license")
+                .help("License file to copy along with the update (firmware updates only)")
+                .required(false)
+                .long("license")
+                .action(ArgAction::Set))
+            .arg(Arg::new("verify-existing")
+                .help("When resuming an extraction, reads back the files already extracted and compares them to their recorded hash before skipping them")
+                .required(false)
+                .long("verify-existing")
+                .action(ArgAction::SetTrue))
+            .arg(no_verify_arg())
+            .arg(eject_arg())
+            .arg(force_arg()))
+        .subcommand(Command::new("verify")
+            .about("Verifies the checksum of a downloaded update and the structure of its archive")
+            .arg(Arg::new("TAR")
+                .help("Update file (tar) to verify")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("destination")
+                .help("Location where the update was extracted, e.g. the USB drive root. Files are read back and compared with the update, corrupted files being extracted again on confirmation.")
+                .required(false)
+                .long("destination")
+                .action(ArgAction::Set)))
+        .subcommand(Command::new("cache")
+            .about("Lists updates in the download directory, and removes obsolete ones")
+            .arg(download_dir_arg())
+            .arg(Arg::new("prune")
+                .help("Removes updates older than the most recent update downloaded for the same software type")
+                .required(false)
+                .long("prune")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("gc")
+                .help("Removes license files left without their update, e.g. after extracting an update while downloading it")
+                .required(false)
+                .long("gc")
+                .action(ArgAction::SetTrue)))
+        .subcommand(Command::new("format")
+            .about("Formats a USB drive, or a disk image file, as FAT32 (all data is erased)")
+            .arg(Arg::new("DEVICE")
+                .help("Block device of the USB drive (e.g. /dev/sdb or /dev/sdb1), or disk image file, to format")
+                .required(true)
+                .index(1))
+            .arg(label_arg())
+            .arg(confirm_arg()))
+        .subcommand(Command::new("disks")
+            .about("Lists available disks")
+            .arg(output_arg())
+            .arg(Arg::new("json")
+                .help("Shorthand for --output json")
+                .required(false)
+                .long("json")
+                .conflicts_with("output")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("removable-only")
+                .help("Only lists removable disks")
+                .required(false)
+                .long("removable-only")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("suitable-for")
+                .help("Only lists disks suitable to extract updates of this size to (removable, empty, FAT32, with enough space), e.g. 12000000000 or 12G")
+                .required(false)
+                .long("suitable-for")
+                .value_parser(disk::parse_size)
+                .action(ArgAction::Set)))
+        .subcommand(Command::new("maps")
+            .about("Lists supported maps"))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    env_logger::init();
+    interrupt::listen();
 
+    let matches = cli().get_matches();
     let interactive = !matches.get_flag("silent");
+
+    let result = match matches.subcommand() {
+        Some(("check", sub_matches)) => check(sub_matches, interactive).await,
+        Some(("download", sub_matches)) => download(sub_matches, interactive).await,
+        Some(("extract", sub_matches)) => extract(sub_matches, interactive),
+        Some(("verify", sub_matches)) => verify(sub_matches, interactive),
+        Some(("cache", sub_matches)) => cache(sub_matches, interactive),
+        Some(("format", sub_matches)) => format(sub_matches, interactive),
+        Some(("disks", sub_matches)) => disks(sub_matches),
+        Some(("maps", _)) => {
+            for map in psa::MAPS {
+                println!("{:<12} {}", map.get_code(), map.get_name());
+            }
+            Ok(())
+        }
+        _ => update(&matches, interactive).await,
+    };
+    if let Err(e) = &result
+        && interrupt::is_interruption(e)
+    {
+        println!();
+        interact::warn(&e.to_string());
+        std::process::exit(interrupt::EXIT_CODE);
+    }
+    if let Err(e) = &result
+        && e.downcast_ref::<disk::UnsuitableDestination>().is_some()
+    {
+        eprintln!("Error: {e:#}. Use --force to extract anyway.");
+        std::process::exit(disk::UNSUITABLE_DESTINATION_EXIT_CODE);
+    }
+    result
+}
+
+fn download_dir(matches: &ArgMatches) -> PathBuf {
+    matches
+        .get_one::<String>("download-dir")
+        .map(PathBuf::from)
+        .unwrap_or_else(cache::default_download_dir)
+}
+
+// Number of updates downloaded at the same time, None for all of them
+fn download_jobs(matches: &ArgMatches) -> Option<usize> {
+    if matches.get_flag("sequential-download") {
+        Some(1)
+    } else {
+        matches.get_one::<u64>("jobs").map(|jobs| *jobs as usize)
+    }
+}
+
+fn download_options(matches: &ArgMatches) -> download::DownloadOptions {
+    let download_dir = download_dir(matches);
+    let rate = matches
+        .get_one::<Option<u64>>("limit-rate")
+        .copied()
+        .flatten();
+    let rate_limiter =
+        throttle::RateLimiter::new(rate, Some(download_dir.join(throttle::CONTROL_FILENAME)));
+    download::DownloadOptions {
+        download_dir,
+        connections: *matches.get_one::<u64>("connections").unwrap(),
+        retries: *matches.get_one::<u32>("retries").unwrap(),
+        retry_delay: Duration::from_secs(*matches.get_one::<u64>("retry-delay").unwrap()),
+        rate_limiter,
+    }
+}
+
+fn http_client() -> Result<Client, Error> {
+    Client::builder()
+        // Dummy user agent to make cloudfront proxy happy when downloading firmware files
+        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/143.0.0.0 Safari/537.36")
+        .build()
+        .context("Failed to create HTTP client")
+}
+
+// Default command: interactive flow going through all steps (check, download, extract)
+async fn update(matches: &ArgMatches, interactive: bool) -> Result<(), Error> {
     let vin = matches.get_one::<String>("VIN").map(|s| s.to_uppercase());
     let vin_provided_as_arg = vin.is_some();
     let map = matches.get_one::<String>("map").map(|s| s.as_str());
     let download = matches.get_flag("download");
-    let sequential_download = matches.get_flag("sequential-download");
+    let jobs = download_jobs(matches);
+    let download_options = download_options(matches);
     let extract_location = matches.get_one::<String>("extract").map(|s| s.as_str());
+    let stream_location = matches.get_one::<String>("stream-to");
+    let usb_drive = matches.get_one::<String>("prepare-usb");
+    if let Some(device) = usb_drive {
+        check_usb_drive(device)?;
+    }
 
     // Vin not provided on command line, asking interactively
     let vin = if !vin_provided_as_arg && interactive {
@@ -82,108 +452,76 @@ async fn main() -> Result<(), Error> {
     }
     let vin = vin.unwrap();
 
-    let client = Client::builder()
-        // Dummy user agent to make cloudfront proxy happy when downloading firmware files
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/143.0.0.0 Safari/537.36")
-        .build()
-        .context("Failed to create HTTP client")?;
-    let device_info = psa::request_device_information(&client, &vin).await?;
-    let is_nac: bool = device_info
-        .devices
-        .map(|l| l.iter().any(|d| d.ecu_type.contains("NAC")))
-        == Some(true);
-
-    // Maps not provided on command line, asking interactively for NAC
-    let map = if map.is_none() && is_nac && interactive {
-        interact::select_map()?
-    } else {
-        map
-    };
+    let client = http_client()?;
 
     println!(
         "\n{}\n",
         style("=== Step 1: Checking for available updates ===").cyan()
     );
-    let update_response = psa::request_available_updates(&client, &vin, map).await?;
-
-    if update_response.software.is_none() {
+    let (devices, software_list) = check_updates(&client, &vin, map, interactive).await?;
+    if software_list.is_empty() {
         println!("No update found");
         return Ok(());
     }
 
-    let mut selected_updates: Vec<psa::SoftwareUpdate> = Vec::new();
-    let mut total_update_size = 0_u64;
-
-    let mut software_list: Vec<psa::Software> = update_response
-        .software
-        .expect("Expected at least a software in server response");
-
-    // For NAC, let's sort in reverse order of software type to display firmware (ovip) first, then map (map)
-    software_list.sort_by(|u1, u2| u2.software_type.cmp(&u1.software_type));
-
-    for software in software_list {
-        for update in &software.update {
-            // An empty update can be sent by the server when there is no available update
-            if !update.update_id.is_empty() {
-                psa::print(&software, update);
-                if download || (interactive && interact::confirm("Download update?")?) {
-                    selected_updates.push(update.clone());
-                    let update_size = match update.update_size.parse() {
-                        Ok(size) => size,
-                        Err(_) => {
-                            debug!("Failed to parse update size: {}", update.update_size);
-                            0
-                        }
-                    };
-                    total_update_size += update_size;
-                }
-            }
-        }
-    }
-
+    let selected_updates = select_updates(&software_list, download, interactive)?;
     if selected_updates.is_empty() {
         println!("No update selected for download");
         return Ok(());
     }
+    let total_update_size = total_update_size(&selected_updates);
+
+    if let Some(location) = stream_location {
+        println!(
+            "\n{}\n",
+            style("=== Step 2: Downloading and extracting updates to USB ===").cyan()
+        );
+        if !stream_updates(
+            &client,
+            &selected_updates,
+            Path::new(location),
+            &download_options,
+            interactive,
+            matches.get_flag("force"),
+        )
+        .await?
+        {
+            return Ok(());
+        }
+        sync_destination(Path::new(location), matches.get_flag("eject"))?;
+        print_instructions(Some(is_nac(&devices)));
+        return Ok(());
+    }
 
     println!(
         "\n{}\n",
         style("=== Step 2: Downloading updates ===").cyan()
     );
-
-    // Check available disk size
-    let disk_space = disk::get_current_dir_available_space();
-    if let Some(space) = disk_space
-        && space < total_update_size
+    let downloaded_updates = match download_updates(
+        &client,
+        &selected_updates,
+        jobs,
+        &download_options,
+        interactive,
+    )
+    .await?
     {
-        interact::warn(&format!(
-            "Not enough space on disk to proceed with download. Available disk space in current directory: {}",
-            DecimalBytes(space)
-        ));
-        if interactive && !(interact::confirm("Continue anyway?")?) {
-            return Ok(());
-        }
-    }
-
-    let multi_progress = MultiProgress::new();
-
-    let downloaded_updates: Vec<psa::DownloadedUpdate> = if sequential_download {
-        // Download sequentially
-        let mut result: Vec<psa::DownloadedUpdate> = Vec::new();
-        for update in selected_updates {
-            result.push(psa::download_update(&client, &update, &multi_progress).await?);
-        }
-        result
-    } else {
-        // Download concurrently
-        let downloads = selected_updates
-            .iter()
-            .map(|update| psa::download_update(&client, update, &multi_progress));
-        try_join_all(downloads).await?
+        Some(downloaded_updates) => downloaded_updates,
+        None => return Ok(()),
     };
 
     let mut extract_location = extract_location.map(str::to_string);
-    if interactive && extract_location.is_none() {
+    // Unmounted once updates are extracted, when mounted in a temporary directory
+    let mut _mount = None;
+    if let Some(device) = usb_drive {
+        println!(
+            "\n{}\n",
+            style("=== Step 3: Preparing USB drive and extracting updates ===").cyan()
+        );
+        let mount = prepare_usb_drive(matches, device, interactive)?;
+        extract_location = Some(mount.mount_point.to_string_lossy().to_string());
+        _mount = Some(mount);
+    } else if interactive && extract_location.is_none() {
         println!(
             "\n{}\n",
             style("=== Step 3: Extracting updates to USB ===").cyan()
@@ -207,45 +545,856 @@ async fn main() -> Result<(), Error> {
         }
     }
 
+    if extract_location.as_deref() == Some("auto") {
+        let filter = disk::DiskFilter {
+            label: matches.get_one::<String>("usb-label").cloned(),
+            min_size: matches.get_one::<u64>("usb-min-size").copied(),
+            max_size: matches.get_one::<u64>("usb-max-size").copied(),
+        };
+        let required_space = downloaded_updates.iter().map(downloaded_size).sum();
+        let location = disk::select_destination(required_space, &filter)?;
+        println!(
+            "\nSelected USB drive {} for extraction",
+            location.to_string_lossy()
+        );
+        extract_location = Some(location.to_string_lossy().to_string());
+    }
+
     match extract_location {
         Some(location) => {
-            let destination_path = Path::new(&location);
-            if !destination_path.is_dir() {
-                return Err(anyhow!(
-                    "Destination does not exist or is not a directory: {}",
-                    destination_path.to_string_lossy()
+            if !extract_updates(
+                &downloaded_updates,
+                Path::new(&location),
+                false,
+                !matches.get_flag("no-verify"),
+                matches.get_flag("force"),
+                interactive,
+            )? {
+                return Ok(());
+            }
+            sync_destination(Path::new(&location), matches.get_flag("eject"))?;
+            print_instructions(Some(is_nac(&devices)));
+        }
+        None => {
+            println!("No location, skipping extraction");
+        }
+    }
+
+    Ok(())
+}
+
+// Check command: only lists available updates
+async fn check(matches: &ArgMatches, interactive: bool) -> Result<(), Error> {
+    let vin = matches.get_one::<String>("VIN").unwrap().to_uppercase();
+    let map = matches.get_one::<String>("map").map(|s| s.as_str());
+    let output = matches.get_one::<String>("output").unwrap();
+
+    let client = http_client()?;
+    let (devices, software_list) = check_updates(&client, &vin, map, interactive).await?;
+    if output == "json" {
+        let report = report::UpdateReport::new(&vin, &devices, &software_list);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("Failed to serialize report")?
+        );
+        return Ok(());
+    }
+    if software_list.is_empty() {
+        println!("No update found");
+        return Ok(());
+    }
+    for software in &software_list {
+        for update in &software.update {
+            // An empty update can be sent by the server when there is no available update
+            if !update.update_id.is_empty() {
+                psa::print(software, update);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Download command: downloads available updates, all of them in silent mode
+async fn download(matches: &ArgMatches, interactive: bool) -> Result<(), Error> {
+    let vin = matches.get_one::<String>("VIN").unwrap().to_uppercase();
+    let map = matches.get_one::<String>("map").map(|s| s.as_str());
+    let jobs = download_jobs(matches);
+    let download_options = download_options(matches);
+    let stream_location = matches.get_one::<String>("stream-to");
+
+    let client = http_client()?;
+    let (devices, software_list) = check_updates(&client, &vin, map, interactive).await?;
+    if software_list.is_empty() {
+        println!("No update found");
+        return Ok(());
+    }
+
+    let selected_updates = select_updates(&software_list, !interactive, interactive)?;
+    if selected_updates.is_empty() {
+        println!("No update selected for download");
+        return Ok(());
+    }
+
+    if let Some(location) = stream_location {
+        if !stream_updates(
+            &client,
+            &selected_updates,
+            Path::new(location),
+            &download_options,
+            interactive,
+            matches.get_flag("force"),
+        )
+        .await?
+        {
+            return Ok(());
+        }
+        sync_destination(Path::new(location), matches.get_flag("eject"))?;
+        print_instructions(Some(is_nac(&devices)));
+        return Ok(());
+    }
+
+    let downloaded_updates = match download_updates(
+        &client,
+        &selected_updates,
+        jobs,
+        &download_options,
+        interactive,
+    )
+    .await?
+    {
+        Some(downloaded_updates) => downloaded_updates,
+        None => return Ok(()),
+    };
+
+    println!("\nDownload complete. To extract the update(s) to a USB drive:");
+    for update in &downloaded_updates {
+        match &update.license_filename {
+            Some(license_filename) => println!(
+                " psa-update extract {} <USB drive root> --license {}",
+                update.update_filename, license_filename
+            ),
+            None => println!(
+                " psa-update extract {} <USB drive root>",
+                update.update_filename
+            ),
+        }
+    }
+    Ok(())
+}
+
+// Cache command: lists downloaded updates, removing obsolete ones if requested
+fn cache(matches: &ArgMatches, interactive: bool) -> Result<(), Error> {
+    let download_dir = download_dir(matches);
+    let entries = cache::list(&download_dir)?;
+    if entries.is_empty() {
+        println!("No update in {}", download_dir.to_string_lossy());
+        return Ok(());
+    }
+    cache::print(&entries);
+
+    let mut removed_entries = Vec::new();
+    if matches.get_flag("prune") {
+        removed_entries.extend(cache::obsolete_entries(&entries));
+    }
+    if matches.get_flag("gc") {
+        for entry in cache::orphan_entries(&entries) {
+            if !removed_entries
+                .iter()
+                .any(|removed| removed.directory == entry.directory)
+            {
+                removed_entries.push(entry);
+            }
+        }
+    }
+    if removed_entries.is_empty() {
+        if matches.get_flag("prune") || matches.get_flag("gc") {
+            println!("\nNothing to remove");
+        }
+        return Ok(());
+    }
+
+    let removed_size: u64 = removed_entries.iter().map(|entry| entry.disk_size).sum();
+    println!(
+        "\nUpdates to remove ({}):",
+        style(DecimalBytes(removed_size)).cyan()
+    );
+    for entry in &removed_entries {
+        println!(" {}", entry.directory.to_string_lossy());
+    }
+    if interactive && !interact::confirm("Remove these updates?")? {
+        return Ok(());
+    }
+    for entry in removed_entries {
+        cache::remove(entry)?;
+    }
+    println!("Removed {}", DecimalBytes(removed_size));
+    Ok(())
+}
+
+// Extract command: extracts a previously downloaded update
+fn extract(matches: &ArgMatches, interactive: bool) -> Result<(), Error> {
+    let update = psa::DownloadedUpdate {
+        license_filename: matches.get_one::<String>("license").cloned(),
+        update_filename: matches.get_one::<String>("TAR").unwrap().clone(),
+    };
+    if let Some(image) = matches.get_one::<String>("image") {
+        return extract_to_image(&update, image, volume_label(matches)?);
+    }
+    let destination = matches.get_one::<String>("DESTINATION").unwrap();
+    let verify_existing = matches.get_flag("verify-existing");
+    let verify = !matches.get_flag("no-verify");
+    let force = matches.get_flag("force");
+    if !extract_updates(
+        &[update],
+        Path::new(destination),
+        verify_existing,
+        verify,
+        force,
+        interactive,
+    )? {
+        return Ok(());
+    }
+    sync_destination(Path::new(destination), matches.get_flag("eject"))?;
+    print_instructions(None);
+    Ok(())
+}
+
+// Extract an update to a new disk image file, to be written to a USB drive afterwards
+fn extract_to_image(
+    update: &psa::DownloadedUpdate,
+    image: &str,
+    label: Option<[u8; 11]>,
+) -> Result<(), Error> {
+    let _guard = interrupt::guard();
+    let files = psa::extract_update_to_image(update, Path::new(image), label).map_err(|e| {
+        if interrupt::is_interruption(&e) {
+            e.context(format!(
+                "Extraction interrupted, incomplete image {image} removed"
+            ))
+        } else {
+            e
+        }
+    })?;
+    let size = fs::metadata(image)
+        .with_context(|| format!("Failed to get metadata of image {image}"))?
+        .len();
+    println!(
+        "{} files extracted to image {image} ({})",
+        files.len(),
+        DecimalBytes(size)
+    );
+    println!(
+        "Write the image to a USB drive of at least {} with a disk imaging tool, e.g. on Linux:\n  dd if={image} of=/dev/sdX bs=4M conv=fsync",
+        DecimalBytes(size)
+    );
+    print_instructions(None);
+    Ok(())
+}
+
+// Verify command: checks the integrity and structure of a previously downloaded update, and optionally the files
+// extracted from it
+fn verify(matches: &ArgMatches, interactive: bool) -> Result<(), Error> {
+    let update_filename = matches.get_one::<String>("TAR").unwrap();
+    psa::verify_checksum(update_filename)?;
+    let report = archive::validate(update_filename)
+        .with_context(|| format!("Failed to verify update {update_filename}"))?;
+    report.print();
+    if !report.is_valid() {
+        return Err(anyhow!("Update {update_filename} is invalid"));
+    }
+    println!("\nUpdate {update_filename} is valid");
+
+    if let Some(destination) = matches.get_one::<String>("destination") {
+        let update = psa::DownloadedUpdate {
+            license_filename: None,
+            update_filename: update_filename.clone(),
+        };
+        let files = archive::hash_files(update_filename)
+            .with_context(|| format!("Failed to verify update {update_filename}"))?;
+        let _guard = interrupt::guard();
+        verify_extracted_update(&update, Path::new(destination), &files, interactive)?;
+    }
+    Ok(())
+}
+
+// Disks command: lists disks, optionally only the ones updates can be extracted to
+fn disks(matches: &ArgMatches) -> Result<(), Error> {
+    let json = matches.get_flag("json") || matches.get_one::<String>("output").unwrap() == "json";
+    let suitable_for = matches.get_one::<u64>("suitable-for").copied();
+    let required_space = suitable_for.unwrap_or(0);
+
+    let mut disks = disk::list_disks();
+    if matches.get_flag("removable-only") {
+        disks.retain(|disk| disk.removable);
+    }
+    if suitable_for.is_some() {
+        disks.retain(|disk| disk.suitability_issues(required_space).is_empty());
+    }
+
+    if json {
+        let report = report::DisksReport::new(&disks, required_space);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("Failed to serialize report")?
+        );
+        return Ok(());
+    }
+    disk::print_disk_table(&disks, required_space);
+    Ok(())
+}
+
+fn format(matches: &ArgMatches, interactive: bool) -> Result<(), Error> {
+    let device = matches.get_one::<String>("DEVICE").unwrap();
+    let label = volume_label(matches)?;
+    let device_path = Path::new(device);
+
+    disk::print_disks(0);
+    println!();
+    format::check_target(device_path)?;
+    let size = format::volume_size(device_path)?;
+    format::check_size(device_path, size)?;
+    confirm_erase(matches, device, size, interactive)?;
+
+    println!("Formatting {device} as FAT32...");
+    let volume = format::format(device_path, label)?;
+    println!(
+        "{device} formatted as FAT32: {} with clusters of {} bytes, {} available",
+        DecimalBytes(volume.size),
+        volume.cluster_size,
+        DecimalBytes(volume.available_space)
+    );
+    Ok(())
+}
+
+fn volume_label(matches: &ArgMatches) -> Result<Option<[u8; 11]>, Error> {
+    matches
+        .get_one::<String>("label")
+        .map(|label| format::volume_label(label))
+        .transpose()
+}
+
+// Check that a USB drive can be prepared before downloading updates, not to find out once downloaded
+fn check_usb_drive(device: &str) -> Result<(), Error> {
+    let device_path = Path::new(device);
+    format::check_target(device_path)?;
+    if disk::is_partition(device_path) {
+        return Err(anyhow!(
+            "{device} is a partition, the whole USB drive is partitioned (e.g. /dev/sdb rather than /dev/sdb1)"
+        ));
+    }
+    let size = format::volume_size(device_path)?;
+    format::check_size(device_path, format::partition_size(size))
+}
+
+// Partition and format a USB drive, and mount it to extract updates to it
+fn prepare_usb_drive(
+    matches: &ArgMatches,
+    device: &str,
+    interactive: bool,
+) -> Result<disk::Mount, Error> {
+    let device_path = Path::new(device);
+    let label = volume_label(matches)?;
+    disk::print_disks(0);
+    println!();
+    let size = format::volume_size(device_path)?;
+    confirm_erase(matches, device, size, interactive)?;
+
+    println!("Partitioning and formatting {device} as FAT32...");
+    let volume = format::prepare(device_path, label)?;
+    println!(
+        "{device} partitioned: FAT32 partition of {} with clusters of {} bytes",
+        DecimalBytes(volume.size),
+        volume.cluster_size
+    );
+    let mount = disk::mount_first_partition(device_path, format::PARTITION_OFFSET)?;
+    println!(
+        "Partition of {device} mounted on {}\n",
+        mount.mount_point.to_string_lossy()
+    );
+    Ok(mount)
+}
+
+// Ask to type the device name again before erasing it, or check the --confirm option in silent mode
+fn confirm_erase(
+    matches: &ArgMatches,
+    device: &str,
+    size: u64,
+    interactive: bool,
+) -> Result<(), Error> {
+    let confirmation = match matches.get_one::<String>("confirm") {
+        Some(confirmation) => confirmation.clone(),
+        None if interactive => interact::prompt(&format!(
+            "All data on {device} ({}) will be erased. Type {device} to confirm",
+            DecimalBytes(size)
+        ))?,
+        None => {
+            return Err(anyhow!(
+                "Erasing {device} requires confirmation, use --confirm {device} in silent mode"
+            ));
+        }
+    };
+    if confirmation.trim() != device {
+        return Err(anyhow!(
+            "Confirmation does not match {device}, nothing was erased"
+        ));
+    }
+    Ok(())
+}
+
+// Request device information and available updates
+// Returns the ECU types of the vehicle devices, and the list of software with available updates sorted for display
+async fn check_updates(
+    client: &Client,
+    vin: &str,
+    map: Option<&str>,
+    interactive: bool,
+) -> Result<(Vec<String>, Vec<psa::Software>), Error> {
+    let device_info = psa::request_device_information(client, vin).await?;
+    let devices: Vec<String> = device_info
+        .devices
+        .map(|l| l.into_iter().map(|d| d.ecu_type).collect())
+        .unwrap_or_default();
+
+    // Maps not provided on command line, asking interactively for NAC
+    let map = if map.is_none() && is_nac(&devices) && interactive {
+        interact::select_map()?
+    } else {
+        map
+    };
+
+    let update_response = psa::request_available_updates(client, vin, map).await?;
+
+    let mut software_list: Vec<psa::Software> = update_response.software.unwrap_or_default();
+
+    // For NAC, let's sort in reverse order of software type to display firmware (ovip) first, then map (map)
+    software_list.sort_by(|u1, u2| u2.software_type.cmp(&u1.software_type));
+
+    Ok((devices, software_list))
+}
+
+fn is_nac(devices: &[String]) -> bool {
+    devices.iter().any(|d| d.contains("NAC"))
+}
+
+// Print available updates, and select those to download
+fn select_updates(
+    software_list: &[psa::Software],
+    download: bool,
+    interactive: bool,
+) -> Result<Vec<psa::SoftwareUpdate>, Error> {
+    let mut selected_updates: Vec<psa::SoftwareUpdate> = Vec::new();
+    for software in software_list {
+        for update in &software.update {
+            // An empty update can be sent by the server when there is no available update
+            if !update.update_id.is_empty() {
+                psa::print(software, update);
+                if download || (interactive && interact::confirm("Download update?")?) {
+                    selected_updates.push(update.clone());
+                }
+            }
+        }
+    }
+    Ok(selected_updates)
+}
+
+fn total_update_size(updates: &[psa::SoftwareUpdate]) -> u64 {
+    updates
+        .iter()
+        .map(|update| match update.update_size.parse() {
+            Ok(size) => size,
+            Err(_) => {
+                debug!("Failed to parse update size: {}", update.update_size);
+                0
+            }
+        })
+        .sum()
+}
+
+// Download selected updates to the download directory, at most jobs at the same time. A failed download does not
+// stop the other ones, the download of every update being reported once all downloads completed.
+// Returns None in case the user aborted the download
+async fn download_updates(
+    client: &Client,
+    selected_updates: &[psa::SoftwareUpdate],
+    jobs: Option<usize>,
+    download_options: &download::DownloadOptions,
+    interactive: bool,
+) -> Result<Option<Vec<psa::DownloadedUpdate>>, Error> {
+    let download_dir = &download_options.download_dir;
+    fs::create_dir_all(download_dir).with_context(|| {
+        format!(
+            "Failed to create download directory {}",
+            download_dir.to_string_lossy()
+        )
+    })?;
+    println!("Downloading to {}", download_dir.to_string_lossy());
+
+    // Check available disk size
+    let total_update_size = total_update_size(selected_updates);
+    let disk_space = disk::get_available_space(download_dir);
+    if let Some(space) = disk_space
+        && space < total_update_size
+    {
+        interact::warn(&format!(
+            "Not enough space on disk to proceed with download. Available disk space in download directory: {}",
+            DecimalBytes(space)
+        ));
+        if interactive && !(interact::confirm("Continue anyway?")?) {
+            return Ok(None);
+        }
+    }
+
+    let multi_progress = MultiProgress::new();
+    let _guard = interrupt::guard();
+
+    let jobs = jobs.unwrap_or(selected_updates.len()).max(1);
+    debug!(
+        "Downloading {} updates, {jobs} at a time",
+        selected_updates.len()
+    );
+    // Starting the next download as soon as any download completes, results being sorted back afterwards
+    let mut results: Vec<(usize, Result<psa::DownloadedUpdate, Error>)> =
+        stream::iter(selected_updates.iter().enumerate())
+            .map(|(index, update)| {
+                psa::download_update(client, update, &multi_progress, download_options)
+                    .map(move |result| (index, result))
+            })
+            .buffer_unordered(jobs)
+            .collect()
+            .await;
+    results.sort_by_key(|(index, _)| *index);
+    let results: Vec<_> = results.into_iter().map(|(_, result)| result).collect();
+
+    // Summary
+    let green = Style::new().green();
+    let yellow = Style::new().yellow();
+    let red = Style::new().red();
+    println!();
+    for (update, result) in selected_updates.iter().zip(&results) {
+        match result {
+            Ok(downloaded_update) => println!(
+                "{} {}",
+                green.apply_to("[downloaded]"),
+                downloaded_update.update_filename
+            ),
+            Err(e) if interrupt::is_interruption(e) => println!(
+                "{} {} {}: {e}",
+                yellow.apply_to("[interrupted]"),
+                update.software_type,
+                update.update_version
+            ),
+            Err(e) => println!(
+                "{} {} {}: {e:#}",
+                red.apply_to("[failed]"),
+                update.software_type,
+                update.update_version
+            ),
+        }
+    }
+
+    if results
+        .iter()
+        .any(|result| result.as_ref().is_err_and(interrupt::is_interruption))
+    {
+        return Err(Error::new(interrupt::Interrupted).context(
+            "Downloads interrupted, run the same command again to resume them from where they stopped",
+        ));
+    }
+    let failure_count = results.iter().filter(|result| result.is_err()).count();
+    if failure_count > 0 {
+        return Err(anyhow!(
+            "Failed to download {failure_count} of {} updates",
+            results.len()
+        ));
+    }
+    Ok(Some(results.into_iter().flatten().collect()))
+}
+
+// Download selected updates and extract them on the fly to destination
+// Returns false in case the user aborted the extraction
+async fn stream_updates(
+    client: &Client,
+    selected_updates: &[psa::SoftwareUpdate],
+    destination_path: &Path,
+    options: &download::DownloadOptions,
+    interactive: bool,
+    force: bool,
+) -> Result<bool, Error> {
+    if !destination_path.is_dir() {
+        return Err(anyhow!(
+            "Destination does not exist or is not a directory: {}",
+            destination_path.to_string_lossy()
+        ));
+    }
+    // License files are small enough to be ignored
+    let required_space = total_update_size(selected_updates);
+    if !force && !check_destination(destination_path, required_space, false, interactive)? {
+        return Ok(false);
+    }
+    let multi_progress = MultiProgress::new();
+    let _guard = interrupt::guard();
+    // Files of the updates streamed before a failure are removed as well, so that the destination is left as it was
+    // and the same command can be run again
+    let existing_entries = psa::list_entries(destination_path)?;
+    // Sequentially, tar archives being extracted in order
+    for update in selected_updates {
+        println!(
+            "\nDownloading and extracting update to {}...",
+            destination_path.to_string_lossy()
+        );
+        if let Err(e) =
+            psa::stream_update(client, update, destination_path, &multi_progress, options).await
+        {
+            psa::remove_new_entries(destination_path, &existing_entries);
+            if interrupt::is_interruption(&e) {
+                return Err(e.context(
+                    "Download and extraction interrupted, streamed updates cannot be resumed: run the same command again to start over",
                 ));
             }
-            for update in downloaded_updates {
-                println!(
-                    "\nExtracting update to {}...",
-                    destination_path.to_string_lossy()
-                );
-                psa::extract_update(&update, destination_path)
-                    .context("Failed to extract update")?;
+            return Err(e.context("Failed to download and extract update"));
+        }
+    }
+    Ok(true)
+}
+
+// Size of the files of a downloaded update, i.e. the space required to extract it
+fn downloaded_size(update: &psa::DownloadedUpdate) -> u64 {
+    std::iter::once(&update.update_filename)
+        .chain(&update.license_filename)
+        .filter_map(|filename| fs::metadata(filename).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// Extract downloaded updates to destination. A failed or interrupted extraction is resumed by extracting again.
+// Unless verify is false, extracted files are then read back and compared with the update.
+// Returns false in case the user aborted the extraction
+fn extract_updates(
+    downloaded_updates: &[psa::DownloadedUpdate],
+    destination_path: &Path,
+    verify_existing: bool,
+    verify: bool,
+    force: bool,
+    interactive: bool,
+) -> Result<bool, Error> {
+    if !destination_path.is_dir() {
+        return Err(anyhow!(
+            "Destination does not exist or is not a directory: {}",
+            destination_path.to_string_lossy()
+        ));
+    }
+    if !force {
+        let mut required_space = 0;
+        let mut resuming = false;
+        for update in downloaded_updates {
+            required_space += downloaded_size(update);
+            // Files extracted by a previous attempt are already on the destination
+            if let Some(extracted_size) =
+                journal::extracted_size(&update.update_filename, destination_path)
+            {
+                required_space = required_space.saturating_sub(extracted_size);
+                resuming = true;
+            }
+        }
+        if !check_destination(destination_path, required_space, resuming, interactive)? {
+            return Ok(false);
+        }
+    }
+    let _guard = interrupt::guard();
+    for (index, update) in downloaded_updates.iter().enumerate() {
+        println!(
+            "\nExtracting update to {}...",
+            destination_path.to_string_lossy()
+        );
+        let files = match psa::extract_update(update, destination_path, verify_existing) {
+            Ok(files) => files,
+            Err(e) => {
+                print_extraction_state(downloaded_updates, index, destination_path, &e);
+                if interrupt::is_interruption(&e) {
+                    return Err(e.context("Extraction interrupted"));
+                }
+                return Err(e.context("Failed to extract update"));
             }
+        };
+        if verify {
+            verify_extracted_update(update, destination_path, &files, interactive)?;
+        }
+    }
+    Ok(true)
+}
+
+// Pre-flight checks of the extraction destination, before writing anything. In interactive mode, the user can choose to
+// extract anyway. Returns false in case the user aborted the extraction
+fn check_destination(
+    destination_path: &Path,
+    required_space: u64,
+    resuming: bool,
+    interactive: bool,
+) -> Result<bool, Error> {
+    let issues = disk::check_destination(destination_path, required_space, resuming);
+    if issues.is_empty() {
+        return Ok(true);
+    }
+    if !interactive {
+        return Err(Error::new(disk::UnsuitableDestination {
+            destination: destination_path.to_string_lossy().to_string(),
+            issues,
+        }));
+    }
+    println!();
+    for issue in &issues {
+        interact::warn(&format!(
+            "Destination {}: {issue}",
+            destination_path.to_string_lossy()
+        ));
+    }
+    interact::confirm(
+        "The destination should be the root of an empty USB drive formatted as FAT32. Extract anyway?",
+    )
+}
+
+// Read back the files of an update extracted to destination and compare them with the update, offering to extract
+// again the files that do not match
+fn verify_extracted_update(
+    update: &psa::DownloadedUpdate,
+    destination_path: &Path,
+    files: &[archive::ArchiveFile],
+    interactive: bool,
+) -> Result<(), Error> {
+    println!(
+        "\nVerifying files extracted to {}...",
+        destination_path.to_string_lossy()
+    );
+    let mismatches = psa::verify_extraction(destination_path, files)?;
+    if mismatches.is_empty() {
+        println!("{} files verified", files.len());
+        return Ok(());
+    }
+    print_mismatches(&mismatches);
+    if !interactive
+        || !interact::confirm(&format!(
+            "Extract the {} corrupted files again?",
+            mismatches.len()
+        ))?
+    {
+        return Err(anyhow!(
+            "{} of {} files extracted to {} do not match update {}",
+            mismatches.len(),
+            files.len(),
+            destination_path.to_string_lossy(),
+            update.update_filename
+        ));
+    }
+
+    let paths: HashSet<PathBuf> = mismatches.into_iter().map(|m| m.path).collect();
+    let files = psa::extract_update_files(update, destination_path, &paths)?;
+    let mismatches = psa::verify_extraction(destination_path, &files)?;
+    if !mismatches.is_empty() {
+        print_mismatches(&mismatches);
+        return Err(anyhow!(
+            "{} files are still corrupted after extracting them again, the USB drive is probably faulty",
+            mismatches.len()
+        ));
+    }
+    println!("{} files extracted again and verified", files.len());
+    Ok(())
+}
+
+fn print_mismatches(mismatches: &[archive::Mismatch]) {
+    for mismatch in mismatches {
+        println!(
+            "{} {}: {}",
+            style("[corrupted]").red(),
+            mismatch.path.to_string_lossy(),
+            mismatch.message
+        );
+    }
+}
+
+// Print the state of each update after extraction failed or was interrupted while extracting the update at the
+// given index, and how to resume it
+fn print_extraction_state(
+    downloaded_updates: &[psa::DownloadedUpdate],
+    index: usize,
+    destination_path: &Path,
+    error: &Error,
+) {
+    println!();
+    for (i, update) in downloaded_updates.iter().enumerate() {
+        if i < index {
             println!(
-                "\n\nExtraction complete. The update can be applied on the car infotainment system:"
+                "{} {}",
+                style("[extracted]").green(),
+                update.update_filename
             );
-            println!(" - Start the car and keep the engine running");
-            println!(" - Insert the USB drive into the car USB port");
+        } else if i == index && interrupt::is_interruption(error) {
+            println!("{} {error:#}", style("[interrupted]").yellow());
+        } else if i == index {
+            println!("{} {error:#}", style("[failed]").red());
+        } else {
             println!(
-                " - Follow the on-screen instructions. Update can take up to 30 minutes depending on the update size"
+                "{} {}",
+                style("[not extracted]").yellow(),
+                update.update_filename
             );
-            if is_nac {
-                println!(
-                    "\nFor more details, refer to vendor instructions. For example, for Peugeot NAC: https://web.archive.org/web/20220719220945/https://media-ct-ndp.peugeot.com/file/38/2/map-software-rcc-en.632382.pdf"
-                );
-            } else {
-                println!(
-                    "\nFor more details, refer to vendor instructions. For example, for Peugeot RCC: https://web.archive.org/web/20230602131011/https://media-ct-ndp.peugeot.com/file/38/0/map-software-nac-en.632380.pdf"
-                );
-            }
         }
-        None => {
-            println!("No location, skipping extraction");
+    }
+    println!(
+        "\n{} contains an incomplete update that must not be applied on the car. To resume the extraction, run:",
+        destination_path.to_string_lossy()
+    );
+    for update in &downloaded_updates[index..] {
+        match &update.license_filename {
+            Some(license_filename) => println!(
+                " psa-update extract {} {} --license {}",
+                update.update_filename,
+                destination_path.to_string_lossy(),
+                license_filename
+            ),
+            None => println!(
+                " psa-update extract {} {}",
+                update.update_filename,
+                destination_path.to_string_lossy()
+            ),
         }
     }
+}
 
+// Make sure that the update is written to the USB drive before announcing completion, the system possibly holding
+// written data in memory. The drive is then ejected if requested.
+fn sync_destination(destination_path: &Path, eject: bool) -> Result<(), Error> {
+    println!(
+        "\nWriting data to {}, do not unplug the USB drive...",
+        destination_path.to_string_lossy()
+    );
+    disk::sync_file_system(destination_path)?;
+    if eject {
+        match disk::eject(destination_path) {
+            Ok(()) => println!("USB drive ejected, it can be unplugged"),
+            Err(e) => interact::warn(&format!(
+                "{e:#}. Please eject the USB drive from the system before unplugging it"
+            )),
+        }
+    }
     Ok(())
 }
+
+// Print instructions to apply the update in the car. Device type might not be known.
+fn print_instructions(is_nac: Option<bool>) {
+    println!("\n\nExtraction complete. The update can be applied on the car infotainment system:");
+    println!(" - Start the car and keep the engine running");
+    println!(" - Insert the USB drive into the car USB port");
+    println!(
+        " - Follow the on-screen instructions. Update can take up to 30 minutes depending on the update size"
+    );
+    if is_nac != Some(false) {
+        println!(
+            "\nFor more details, refer to vendor instructions. For example, for Peugeot NAC: https://web.archive.org/web/20220719220945/https://media-ct-ndp.peugeot.com/file/38/2/map-software-rcc-en.632382.pdf"
+        );
+    }
+    if is_nac != Some(true) {
+        println!(
+            "\nFor more details, refer to vendor instructions. For example, for Peugeot RCC: https://web.archive.org/web/20230602131011/https://media-ct-ndp.peugeot.com/file/38/0/map-software-nac-en.632380.pdf"
+        );
+    }
+}
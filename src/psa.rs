@@ -1,8 +1,12 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::mpsc;
 
 use serde::{Deserialize, Serialize};
 
@@ -14,10 +18,15 @@ use console::Style;
 
 use indicatif::{DecimalBytes, MultiProgress};
 
-use tar::Archive;
-
+use crate::archive;
+use crate::archive::ArchiveFile;
+use crate::cache;
+use crate::checksum;
+use crate::disk;
 use crate::download;
+use crate::format;
 use crate::interact;
+use crate::journal::ExtractionJournal;
 
 // URL to query vehicle device: NAC or RCC
 const DEVICE_URL: &str = "https://api.groupe-psa.com/applications/majesticf/v1/devices/{VIN}?client_id=20a4cf7c-f5fb-41d5-9175-a6e23b9880e5";
@@ -173,6 +182,9 @@ pub struct SoftwareUpdate {
     pub update_url: String,
     #[serde(rename = "licenseURL")]
     pub license_url: String,
+    // Not sent by the server along with the update, copied from the software
+    #[serde(skip)]
+    pub software_type: String,
 }
 
 #[derive(Debug)]
@@ -318,8 +330,13 @@ pub async fn request_available_updates(
     let response_text = response.text().await?;
     debug!("Received response body {response_text}");
 
-    let update_response: UpdateResponse = serde_json::from_str(&response_text)
+    let mut update_response: UpdateResponse = serde_json::from_str(&response_text)
         .with_context(|| format!("Failed to parse response: {response_text}"))?;
+    for software in update_response.software.iter_mut().flatten() {
+        for update in &mut software.update {
+            update.software_type = software.software_type.clone();
+        }
+    }
 
     if update_response.request_result != "OK" {
         Err(anyhow!(
@@ -335,37 +352,314 @@ pub async fn download_update(
     client: &reqwest::Client,
     software_update: &SoftwareUpdate,
     multi_progress: &MultiProgress,
+    options: &download::DownloadOptions,
 ) -> Result<DownloadedUpdate, Error> {
     debug!("Downloading update {software_update:?}");
+    let directory = update_dir(software_update, options)?;
     let license_filename = if software_update.license_url.is_empty() {
         None
     } else {
         Some(
-            download::download_file(client, &software_update.license_url, multi_progress, false)
-                .await?,
+            download::download_file(
+                client,
+                &software_update.license_url,
+                &directory,
+                multi_progress,
+                false,
+                options,
+            )
+            .await?,
         )
     };
-    let update_filename =
-        download::download_file(client, &software_update.update_url, multi_progress, true).await?;
+    // Recorded before downloading the update, so that a partial download is listed in the cache
+    cache::write_metadata(
+        &directory,
+        software_update,
+        license_filename.as_deref(),
+        None,
+    )?;
+    let update_filename = download::download_file(
+        client,
+        &software_update.update_url,
+        &directory,
+        multi_progress,
+        true,
+        options,
+    )
+    .await?;
+    cache::write_metadata(
+        &directory,
+        software_update,
+        license_filename.as_deref(),
+        Some(&update_filename),
+    )?;
     Ok(DownloadedUpdate {
         license_filename,
         update_filename,
     })
 }
 
-// Extract firmware update to specified location
-pub fn extract_update(update: &DownloadedUpdate, destination_path: &Path) -> Result<(), Error> {
-    if let Some(license_filename) = &update.license_filename {
-        debug!("Copying licence file");
-        let licence_destination_path = destination_path.join("license");
-        fs::create_dir(&licence_destination_path).with_context(|| {
+// Create the directory where an update is downloaded
+fn update_dir(
+    software_update: &SoftwareUpdate,
+    options: &download::DownloadOptions,
+) -> Result<PathBuf, Error> {
+    let directory = cache::update_dir(&options.download_dir, software_update);
+    fs::create_dir_all(&directory).with_context(|| {
+        format!(
+            "Failed to create download directory {}",
+            directory.to_string_lossy()
+        )
+    })?;
+    Ok(directory)
+}
+
+// Download an update and extract it on the fly to the specified location, without storing the update locally
+pub async fn stream_update(
+    client: &reqwest::Client,
+    software_update: &SoftwareUpdate,
+    destination_path: &Path,
+    multi_progress: &MultiProgress,
+    options: &download::DownloadOptions,
+) -> Result<(), Error> {
+    if !software_update.license_url.is_empty() {
+        // License is small enough to be stored locally
+        let directory = update_dir(software_update, options)?;
+        let license_filename = download::download_file(
+            client,
+            &software_update.license_url,
+            &directory,
+            multi_progress,
+            false,
+            options,
+        )
+        .await?;
+        cache::write_metadata(&directory, software_update, Some(&license_filename), None)?;
+        copy_license(&license_filename, destination_path)?;
+    }
+
+    // Bounded channel so that download waits for extraction to the (slower) USB drive
+    let (sender, receiver) = mpsc::sync_channel(64);
+    let destination = destination_path.to_path_buf();
+    let extraction = tokio::task::spawn_blocking(move || {
+        let mut reader = download::ChannelReader::new(receiver);
+        archive::extract(&mut reader, &destination, None)?;
+        // Consume the padding after the end of the archive, so that the download completes and is verified
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok::<(), Error>(())
+    });
+
+    let download_result = download::stream_file(
+        client,
+        &software_update.update_url,
+        multi_progress,
+        sender,
+        options,
+    )
+    .await;
+    let extraction_result = extraction.await.context("Extraction task failed")?;
+    // Extraction fails as well when download fails, download error being the root cause
+    let filename = download_result?;
+    extraction_result.with_context(|| {
+        format!(
+            "Failed to extract {} to {}",
+            filename,
+            destination_path.to_string_lossy()
+        )
+    })
+}
+
+// Names of the files and directories at the root of a directory
+pub fn list_entries(path: &Path) -> Result<HashSet<OsString>, Error> {
+    let entries = fs::read_dir(path)
+        .with_context(|| format!("Failed to list files in {}", path.to_string_lossy()))?;
+    Ok(entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name())
+        .collect())
+}
+
+// Remove the files and directories created at the root of a directory, restoring it to its previous state
+pub fn remove_new_entries(path: &Path, existing_entries: &HashSet<OsString>) {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            interact::warn(&format!(
+                "Failed to list files in {} for cleanup: {e}",
+                path.to_string_lossy()
+            ));
+            return;
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if existing_entries.contains(&entry.file_name()) {
+            continue;
+        }
+        let entry_path = entry.path();
+        debug!("Removing {}", entry_path.to_string_lossy());
+        let result = if entry_path.is_dir() {
+            fs::remove_dir_all(&entry_path)
+        } else {
+            fs::remove_file(&entry_path)
+        };
+        if let Err(e) = result {
+            interact::warn(&format!(
+                "Failed to remove {}: {e}",
+                entry_path.to_string_lossy()
+            ));
+        }
+    }
+    println!(
+        "Removed partially extracted files from {}",
+        path.to_string_lossy()
+    );
+}
+
+// Check an update before extracting it. Returns the report of the validation of its archive.
+fn check_update(update: &DownloadedUpdate) -> Result<archive::ArchiveReport, Error> {
+    // Refuse to extract a corrupted download
+    verify_checksum(&update.update_filename)?;
+
+    // Refuse to extract an archive that would fail in the middle of the extraction, leaving the destination half-filled
+    debug!("Validating tar file");
+    let report = archive::validate(&update.update_filename)?;
+    if !report.is_valid() {
+        report.print();
+        return Err(anyhow!(
+            "Archive {} is invalid, nothing was extracted",
+            update.update_filename
+        ));
+    }
+    Ok(report)
+}
+
+// Extract firmware update to a new disk image file, partitioned and formatted as FAT32 and sized to fit the update.
+// The image is written without being mounted, and can then be written as is to USB drives. It is removed when the
+// extraction fails.
+pub fn extract_update_to_image(
+    update: &DownloadedUpdate,
+    image_path: &Path,
+    label: Option<[u8; 11]>,
+) -> Result<Vec<ArchiveFile>, Error> {
+    let report = check_update(update)?;
+    let license_size = match &update.license_filename {
+        Some(license_filename) => fs::metadata(license_filename)
+            .with_context(|| format!("Failed to get metadata of file {license_filename}"))?
+            .len(),
+        None => 0,
+    };
+    // License directory and file included
+    let image_size = format::image_size(report.total_size + license_size, report.entry_count + 2);
+    format::create_image(image_path, image_size, label)?;
+
+    let result = format::with_image_file_system(image_path, |file_system| {
+        let root = file_system.root_dir();
+        if let Some(license_filename) = &update.license_filename {
+            copy_license_to_fat(license_filename, &root)?;
+        }
+
+        debug!("Extracting tar file to image");
+        let tar_file = File::open(&update.update_filename)
+            .with_context(|| format!("Failed to open firmware {}", update.update_filename))?;
+        let progress_bar = interact::progress_bar(tar_file.metadata()?.len());
+        progress_bar.set_message(update.update_filename.to_string()); // Triggers first draw
+        let buffered_reader = BufReader::with_capacity(1024 * 1024, tar_file);
+        let mut progress_reader = progress_bar.wrap_read(buffered_reader);
+        let files = archive::extract_to_fat(&mut progress_reader, &root);
+        match &files {
+            Ok(_) => progress_bar.finish(),
+            Err(_) => progress_bar.abandon(),
+        }
+        files
+    })
+    .with_context(|| {
+        format!(
+            "Failed to extract tar {} to image {}",
+            update.update_filename,
+            image_path.to_string_lossy()
+        )
+    });
+    if result.is_err() {
+        debug!("Removing incomplete image {}", image_path.to_string_lossy());
+        if let Err(e) = fs::remove_file(image_path) {
+            debug!("Failed to remove image: {e}");
+        }
+    }
+    result
+}
+
+fn copy_license_to_fat<T: fatfs::ReadWriteSeek>(
+    license_filename: &str,
+    root: &fatfs::Dir<T>,
+) -> Result<(), Error> {
+    debug!("Copying licence file to image");
+    let name = Path::new(license_filename)
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid license file name: {license_filename}"))?
+        .to_string_lossy();
+    let mut license_file = File::open(license_filename)
+        .with_context(|| format!("Failed to open license file {license_filename}"))?;
+    let mut licence_destination = root
+        .create_dir("license")
+        .and_then(|directory| directory.create_file(&name))
+        .context("Failed to create license file in image")?;
+    io::copy(&mut license_file, &mut licence_destination)
+        .context("Failed to copy license file to image")?;
+    Ok(())
+}
+
+fn copy_license(license_filename: &str, destination_path: &Path) -> Result<(), Error> {
+    debug!("Copying licence file");
+    let licence_destination_path = destination_path.join("license");
+    // Already existing when resuming an extraction
+    fs::create_dir_all(&licence_destination_path).with_context(|| {
+        format!(
+            "Failed to create directory {}",
+            licence_destination_path.to_string_lossy()
+        )
+    })?;
+    // License might have been provided as a path, only keeping the file name
+    let licence_destination = match Path::new(license_filename).file_name() {
+        Some(name) => licence_destination_path.join(name),
+        None => return Err(anyhow!("Invalid license file name: {license_filename}")),
+    };
+    fs::copy(license_filename, &licence_destination)?;
+    // Written to the device right away, as extracted files are. Opened for writing, as required to sync on Windows.
+    File::options()
+        .write(true)
+        .open(&licence_destination)
+        .and_then(|file| file.sync_all())
+        .with_context(|| {
             format!(
-                "Failed to create directory {}",
-                licence_destination_path.to_string_lossy()
+                "Failed to write file {}",
+                licence_destination.to_string_lossy()
             )
         })?;
-        let licence_destination = licence_destination_path.join(license_filename);
-        fs::copy(license_filename, licence_destination)?;
+    disk::sync_directory(&licence_destination_path)?;
+    disk::sync_directory(destination_path)
+}
+
+// Extract firmware update to specified location, resuming a previous extraction if any. When verify_existing is set,
+// files extracted by the previous extraction are read back and compared to the recorded hash before skipping them.
+// Returns the files of the update, to verify the extraction.
+pub fn extract_update(
+    update: &DownloadedUpdate,
+    destination_path: &Path,
+    verify_existing: bool,
+) -> Result<Vec<ArchiveFile>, Error> {
+    check_update(update)?;
+
+    if let Some(license_filename) = &update.license_filename {
+        copy_license(license_filename, destination_path)?;
+    }
+
+    let mut journal =
+        ExtractionJournal::open(&update.update_filename, destination_path, verify_existing)?;
+    if journal.extracted_count() > 0 {
+        println!(
+            "Resuming extraction, skipping {} files already extracted",
+            journal.extracted_count()
+        );
     }
 
     debug!("Extracting tar file");
@@ -384,15 +678,71 @@ pub fn extract_update(update: &DownloadedUpdate, destination_path: &Path) -> Res
     let mut progress_reader = progress_bar.wrap_read(buffered_reader);
 
     // Extract tar archive
-    let mut ar = Archive::new(&mut progress_reader);
-    ar.unpack(destination_path).with_context(|| {
+    let files = archive::extract(&mut progress_reader, destination_path, Some(&mut journal))
+        .with_context(|| {
+            format!(
+                "Failed to extract tar {} to {} ",
+                update.update_filename,
+                destination_path.to_string_lossy()
+            )
+        })?;
+    progress_bar.finish();
+    journal.remove()?;
+
+    Ok(files)
+}
+
+// Extract again some files of an update, e.g. files corrupted on the USB drive
+pub fn extract_update_files(
+    update: &DownloadedUpdate,
+    destination_path: &Path,
+    paths: &HashSet<PathBuf>,
+) -> Result<Vec<ArchiveFile>, Error> {
+    let tar_file = File::open(&update.update_filename)
+        .with_context(|| format!("Failed to open firmware {}", update.update_filename))?;
+    let tar_file_size = tar_file
+        .metadata()
+        .context("Failed to get tar file metadata")?
+        .len();
+    let progress_bar = interact::progress_bar(tar_file_size);
+    progress_bar.set_message(update.update_filename.to_string());
+    let buffered_reader = BufReader::with_capacity(1024 * 1024, tar_file);
+    let files = archive::extract_files(
+        progress_bar.wrap_read(buffered_reader),
+        destination_path,
+        paths,
+    )
+    .with_context(|| {
         format!(
-            "Failed to extract tar {} to {} ",
+            "Failed to extract tar {} to {}",
             update.update_filename,
             destination_path.to_string_lossy()
         )
     })?;
     progress_bar.finish();
+    Ok(files)
+}
+
+// Read back the files of an update extracted to destination, and compare them with the update ones
+pub fn verify_extraction(
+    destination_path: &Path,
+    files: &[ArchiveFile],
+) -> Result<Vec<archive::Mismatch>, Error> {
+    let progress_bar = interact::progress_bar(0);
+    progress_bar.set_message(format!(
+        "Verifying files in {}",
+        destination_path.to_string_lossy()
+    ));
+    archive::verify_extraction(destination_path, files, &progress_bar)
+}
 
+// Compare a firmware update to the checksum recorded after download
+pub fn verify_checksum(update_filename: &str) -> Result<(), Error> {
+    debug!("Verifying checksum of {update_filename}");
+    if !checksum::verify_manifest(update_filename)? {
+        interact::warn(&format!(
+            "No checksum recorded for {update_filename}, unable to verify its integrity"
+        ));
+    }
     Ok(())
 }
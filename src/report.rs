@@ -0,0 +1,357 @@
+use serde::Serialize;
+
+use log::debug;
+
+use crate::disk::DiskInfo;
+use crate::psa::{Software, SoftwareUpdate};
+
+// Version of the JSON document below. To be increased on any incompatible change (field removed, renamed or with a
+// different type). Adding a new field is not considered an incompatible change.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/*
+Machine-readable report of an update check, printed by `psa-update check <VIN> --output json`.
+Unlike the server response, field names and types are stable. Sample report (schema version 1):
+{
+    "schemaVersion": 1,
+    "vin": "xxx",
+    "devices": ["NAC_EUR_WAVE2"],
+    "software": [{
+        "softwareType": "ovip-int-firmware-version",
+        "currentVersion": "21.07.67.32_NAC-r0",
+        "updates": [{
+            "id": "001315031613548831",
+            "version": "21.08.87.32_NAC-r1",
+            "size": 2730659840,
+            "date": "2021-04-19 17:38:57.0",
+            "url": "https://majestic-web.mpsa.com/mjf00-web/rest/UpdateDownload?updateId=001315031613548831&uin=0D011C0939D4EE8027F4&type=fw",
+            "licenseUrl": "https://majestic-web.mpsa.com/mjf00-web/rest/LicenseDownload?mediaVersion=001315031613548831&uin=0D011C0939D4EE8027F4"
+        }]
+    }]
+}
+- devices: ECU types of the vehicle devices, e.g. NAC_EUR_WAVE2
+- size: update size in bytes, null if the server sent an invalid size
+- licenseUrl: null when the update does not require a license (maps, RCC firmware)
+- updates: empty when no update is available for the software
+ */
+#[derive(Debug, Serialize)]
+pub struct UpdateReport {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    pub vin: String,
+    pub devices: Vec<String>,
+    pub software: Vec<SoftwareReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SoftwareReport {
+    #[serde(rename = "softwareType")]
+    pub software_type: String,
+    #[serde(rename = "currentVersion")]
+    pub current_version: String,
+    pub updates: Vec<UpdateReportEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdateReportEntry {
+    pub id: String,
+    pub version: String,
+    pub size: Option<u64>,
+    pub date: String,
+    pub url: String,
+    #[serde(rename = "licenseUrl")]
+    pub license_url: Option<String>,
+}
+
+impl UpdateReport {
+    pub fn new(vin: &str, devices: &[String], software_list: &[Software]) -> UpdateReport {
+        UpdateReport {
+            schema_version: SCHEMA_VERSION,
+            vin: vin.to_string(),
+            devices: devices.to_vec(),
+            software: software_list.iter().map(SoftwareReport::new).collect(),
+        }
+    }
+}
+
+impl SoftwareReport {
+    fn new(software: &Software) -> SoftwareReport {
+        SoftwareReport {
+            software_type: software.software_type.clone(),
+            current_version: software.current_software_version.clone(),
+            updates: software
+                .update
+                .iter()
+                // An empty update can be sent by the server when there is no available update
+                .filter(|update| !update.update_id.is_empty())
+                .map(UpdateReportEntry::new)
+                .collect(),
+        }
+    }
+}
+
+impl UpdateReportEntry {
+    fn new(update: &SoftwareUpdate) -> UpdateReportEntry {
+        let size = match update.update_size.parse() {
+            Ok(size) => Some(size),
+            Err(_) => {
+                debug!("Failed to parse update size: {}", update.update_size);
+                None
+            }
+        };
+        let license_url = if update.license_url.is_empty() {
+            None
+        } else {
+            Some(update.license_url.clone())
+        };
+        UpdateReportEntry {
+            id: update.update_id.clone(),
+            version: update.update_version.clone(),
+            size,
+            date: update.update_date.clone(),
+            url: update.update_url.clone(),
+            license_url,
+        }
+    }
+}
+
+// Version of the disks JSON document below, following the same rules as SCHEMA_VERSION
+pub const DISKS_SCHEMA_VERSION: u32 = 1;
+
+/*
+Machine-readable list of disks, printed by `psa-update disks --json`. Sample report (schema version 1):
+{
+    "schemaVersion": 1,
+    "requiredSpace": 0,
+    "disks": [{
+        "name": "/dev/sdb1",
+        "mountPoint": "/media/user/PSA",
+        "fileSystem": "vfat",
+        "label": "PSA",
+        "removable": true,
+        "totalSpace": 31448350720,
+        "availableSpace": 31448334336,
+        "empty": true,
+        "suitable": true,
+        "issues": []
+    }]
+}
+- requiredSpace: space required to extract updates, in bytes, as given with --suitable-for, 0 otherwise
+- label: null when the disk has no label, or when it could not be determined
+- empty: null when files of the disk could not be listed
+- suitable: whether updates requiring requiredSpace can be extracted to the disk, issues listing why not
+ */
+#[derive(Debug, Serialize)]
+pub struct DisksReport {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    #[serde(rename = "requiredSpace")]
+    pub required_space: u64,
+    pub disks: Vec<DiskReportEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskReportEntry {
+    pub name: String,
+    #[serde(rename = "mountPoint")]
+    pub mount_point: String,
+    #[serde(rename = "fileSystem")]
+    pub file_system: String,
+    pub label: Option<String>,
+    pub removable: bool,
+    #[serde(rename = "totalSpace")]
+    pub total_space: u64,
+    #[serde(rename = "availableSpace")]
+    pub available_space: u64,
+    pub empty: Option<bool>,
+    pub suitable: bool,
+    pub issues: Vec<String>,
+}
+
+impl DisksReport {
+    pub fn new(disks: &[DiskInfo], required_space: u64) -> DisksReport {
+        DisksReport {
+            schema_version: DISKS_SCHEMA_VERSION,
+            required_space,
+            disks: disks
+                .iter()
+                .map(|disk| DiskReportEntry::new(disk, required_space))
+                .collect(),
+        }
+    }
+}
+
+impl DiskReportEntry {
+    fn new(disk: &DiskInfo, required_space: u64) -> DiskReportEntry {
+        let issues = disk.suitability_issues(required_space);
+        DiskReportEntry {
+            name: disk.name.clone(),
+            mount_point: disk.mount_point.to_string_lossy().to_string(),
+            file_system: disk.file_system.clone(),
+            label: disk.label.clone(),
+            removable: disk.removable,
+            total_space: disk.total_space,
+            available_space: disk.available_space,
+            empty: disk.empty,
+            suitable: issues.is_empty(),
+            issues,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use serde_json::{Value, json};
+
+    fn software_list() -> Vec<Software> {
+        serde_json::from_value(json!([{
+            "softwareType": "map-eur",
+            "updateRequestResult": "OK",
+            "currentSoftwareVersion": "14.0.0-r0",
+            "update": [{
+                "updateId": "002315011610132966",
+                "updateSize": "unknown",
+                "updateVersion": "20.0.0-r0",
+                "updateDate": "2021-02-07 11:47:22.0",
+                "updateURL": "http://download.tomtom.com/map.tar",
+                "licenseURL": ""
+            }]
+        }, {
+            "softwareType": "ovip-int-firmware-version",
+            "updateRequestResult": "OK",
+            "currentSoftwareVersion": "21.07.67.32_NAC-r0",
+            "update": [{
+                "updateId": "001315031613548831",
+                "updateSize": "2730659840",
+                "updateVersion": "21.08.87.32_NAC-r1",
+                "updateDate": "2021-04-19 17:38:57.0",
+                "updateURL": "https://majestic-web.mpsa.com/update",
+                "licenseURL": "https://majestic-web.mpsa.com/license"
+            }]
+        }, {
+            "softwareType": "rcc-firmware",
+            "updateRequestResult": "OK",
+            "currentSoftwareVersion": "1.0",
+            "update": [{
+                "updateId": "",
+                "updateSize": "",
+                "updateVersion": "",
+                "updateDate": "",
+                "updateURL": "",
+                "licenseURL": ""
+            }]
+        }]))
+        .unwrap()
+    }
+
+    #[test]
+    fn update_report_fields_are_stable() {
+        let report = UpdateReport::new("VIN", &["NAC_EUR_WAVE2".to_string()], &software_list());
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "schemaVersion": 1,
+                "vin": "VIN",
+                "devices": ["NAC_EUR_WAVE2"],
+                "software": [{
+                    "softwareType": "map-eur",
+                    "currentVersion": "14.0.0-r0",
+                    "updates": [{
+                        "id": "002315011610132966",
+                        "version": "20.0.0-r0",
+                        "size": null,
+                        "date": "2021-02-07 11:47:22.0",
+                        "url": "http://download.tomtom.com/map.tar",
+                        "licenseUrl": null
+                    }]
+                }, {
+                    "softwareType": "ovip-int-firmware-version",
+                    "currentVersion": "21.07.67.32_NAC-r0",
+                    "updates": [{
+                        "id": "001315031613548831",
+                        "version": "21.08.87.32_NAC-r1",
+                        "size": 2730659840u64,
+                        "date": "2021-04-19 17:38:57.0",
+                        "url": "https://majestic-web.mpsa.com/update",
+                        "licenseUrl": "https://majestic-web.mpsa.com/license"
+                    }]
+                }, {
+                    "softwareType": "rcc-firmware",
+                    "currentVersion": "1.0",
+                    "updates": []
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn disks_report_fields_are_stable() {
+        let disks = [
+            DiskInfo {
+                name: "/dev/sdb1".to_string(),
+                mount_point: PathBuf::from("/media/user/PSA"),
+                file_system: "vfat".to_string(),
+                label: Some("PSA".to_string()),
+                removable: true,
+                total_space: 32_000_000_000,
+                available_space: 31_000_000_000,
+                empty: Some(true),
+            },
+            DiskInfo {
+                name: "/dev/sdc1".to_string(),
+                mount_point: PathBuf::from("/media/user/disk"),
+                file_system: "vfat".to_string(),
+                label: None,
+                removable: true,
+                total_space: 8_000_000_000,
+                available_space: 8_000_000_000,
+                empty: None,
+            },
+        ];
+        let report = DisksReport::new(&disks, 10_000_000_000);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "schemaVersion": 1,
+                "requiredSpace": 10_000_000_000u64,
+                "disks": [{
+                    "name": "/dev/sdb1",
+                    "mountPoint": "/media/user/PSA",
+                    "fileSystem": "vfat",
+                    "label": "PSA",
+                    "removable": true,
+                    "totalSpace": 32_000_000_000u64,
+                    "availableSpace": 31_000_000_000u64,
+                    "empty": true,
+                    "suitable": true,
+                    "issues": []
+                }, {
+                    "name": "/dev/sdc1",
+                    "mountPoint": "/media/user/disk",
+                    "fileSystem": "vfat",
+                    "label": null,
+                    "removable": true,
+                    "totalSpace": 8_000_000_000u64,
+                    "availableSpace": 8_000_000_000u64,
+                    "empty": null,
+                    "suitable": false,
+                    "issues": [
+                        "not enough space, 8.00 GB available but 10.00 GB required",
+                        "failed to list files"
+                    ]
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn empty_disk_list_is_an_empty_array() {
+        let report = serde_json::to_value(DisksReport::new(&[], 0)).unwrap();
+        assert_eq!(report["disks"], Value::Array(Vec::new()));
+        assert_eq!(report["requiredSpace"], json!(0));
+    }
+}
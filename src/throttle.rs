@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::units::{Base, parse_bytes};
+
+// Name of the control file in the download directory
+pub const CONTROL_FILENAME: &str = "limit-rate";
+
+// Interval between two reads of the control file
+const CONTROL_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Limit of the bandwidth used by all downloads together: token bucket shared by the downloads, refilled at the
+// given rate. The limit can be changed at runtime by writing a rate to a control file, and reverts to the initial
+// limit once the file is removed.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    // Bytes per second, None when unlimited
+    rate: Option<u64>,
+    initial_rate: Option<u64>,
+    // Negative when downloads consumed more than available, i.e. downloads have to wait
+    tokens: f64,
+    last_refill: Instant,
+    control_file: Option<PathBuf>,
+    last_control_file_check: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>, control_file: Option<PathBuf>) -> RateLimiter {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                initial_rate: rate,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+                control_file,
+                last_control_file_check: None,
+            })),
+        }
+    }
+
+    // Account for downloaded bytes, waiting as long as needed not to exceed the rate
+    pub async fn consume(&self, bytes: u64) {
+        let delay = {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.check_control_file();
+            bucket.refill();
+            match bucket.rate {
+                Some(rate) => {
+                    bucket.tokens -= bytes as f64;
+                    if bucket.tokens < 0.0 {
+                        Duration::from_secs_f64(-bucket.tokens / rate as f64)
+                    } else {
+                        Duration::ZERO
+                    }
+                }
+                _ => Duration::ZERO,
+            }
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        match self.rate {
+            // Allowing bursts of at most one second
+            Some(rate) => self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64),
+            None => self.tokens = 0.0,
+        }
+    }
+
+    fn check_control_file(&mut self) {
+        let Some(control_file) = &self.control_file else {
+            return;
+        };
+        if self
+            .last_control_file_check
+            .is_some_and(|last_check| last_check.elapsed() < CONTROL_FILE_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_control_file_check = Some(Instant::now());
+
+        let rate = match fs::read_to_string(control_file) {
+            Ok(content) => match parse_rate(content.trim()) {
+                Ok(rate) => rate,
+                Err(e) => {
+                    debug!(
+                        "Ignoring invalid rate in {}: {e}",
+                        control_file.to_string_lossy()
+                    );
+                    return;
+                }
+            },
+            // No control file: initial limit
+            Err(_) => self.initial_rate,
+        };
+        if rate != self.rate {
+            debug!("Download rate limit changed to {rate:?} bytes per second");
+            self.rate = rate;
+            self.tokens = 0.0;
+        }
+    }
+}
+
+// Parse a rate in bytes per second, with an optional K, M or G suffix (multiples of 1024), e.g. 500K or 1.5M.
+// Zero means unlimited.
+pub fn parse_rate(value: &str) -> Result<Option<u64>, String> {
+    let rate = parse_bytes(value, Base::Binary)
+        .ok_or_else(|| format!("Invalid rate {value}, expecting e.g. 500K or 5M"))?;
+    Ok(if rate == 0 { None } else { Some(rate) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_after_check(limiter: &RateLimiter) -> Option<u64> {
+        let mut bucket = limiter.bucket.lock().unwrap();
+        // Not waiting for the check interval
+        bucket.last_control_file_check = None;
+        bucket.check_control_file();
+        bucket.rate
+    }
+
+    #[test]
+    fn rates_are_multiples_of_1024() {
+        assert_eq!(parse_rate("500"), Ok(Some(500)));
+        assert_eq!(parse_rate("500K"), Ok(Some(500 * 1024)));
+        assert_eq!(parse_rate("1.5M"), Ok(Some(1536 * 1024)));
+        assert_eq!(parse_rate("2G"), Ok(Some(2 * 1024 * 1024 * 1024)));
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        assert_eq!(parse_rate("0"), Ok(None));
+        assert_eq!(parse_rate("0K"), Ok(None));
+        // Less than a byte per second
+        assert_eq!(parse_rate("0.5"), Ok(None));
+    }
+
+    #[test]
+    fn invalid_rates_are_refused() {
+        assert_eq!(
+            parse_rate("fast"),
+            Err("Invalid rate fast, expecting e.g. 500K or 5M".to_string())
+        );
+        assert!(parse_rate("-1M").is_err());
+        assert!(parse_rate("18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn control_file_changes_the_rate() {
+        let directory = tempfile::tempdir().unwrap();
+        let control_file = directory.path().join(CONTROL_FILENAME);
+        let limiter = RateLimiter::new(Some(1000), Some(control_file.clone()));
+        assert_eq!(rate_after_check(&limiter), Some(1000));
+
+        fs::write(&control_file, "2K\n").unwrap();
+        assert_eq!(rate_after_check(&limiter), Some(2048));
+        fs::write(&control_file, "0").unwrap();
+        assert_eq!(rate_after_check(&limiter), None);
+        // Invalid rate is ignored, keeping the current one
+        fs::write(&control_file, "3K").unwrap();
+        assert_eq!(rate_after_check(&limiter), Some(3072));
+        fs::write(&control_file, "fast").unwrap();
+        assert_eq!(rate_after_check(&limiter), Some(3072));
+        // Back to the initial rate once removed
+        fs::remove_file(&control_file).unwrap();
+        assert_eq!(rate_after_check(&limiter), Some(1000));
+    }
+
+    #[test]
+    fn control_file_is_not_read_again_before_interval() {
+        let directory = tempfile::tempdir().unwrap();
+        let control_file = directory.path().join(CONTROL_FILENAME);
+        let limiter = RateLimiter::new(None, Some(control_file.clone()));
+        assert_eq!(rate_after_check(&limiter), None);
+        fs::write(&control_file, "1M").unwrap();
+        let mut bucket = limiter.bucket.lock().unwrap();
+        bucket.check_control_file();
+        assert_eq!(bucket.rate, None);
+    }
+}
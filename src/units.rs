@@ -0,0 +1,84 @@
+// Parsing of the sizes and rates given on the command line, e.g. 16G or 500K
+
+// Multiple of the K, M, G and T suffixes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Base {
+    // Powers of 1000, as disk sizes are listed by the system
+    Decimal,
+    // Powers of 1024
+    Binary,
+}
+
+// Parse a number of bytes with an optional K, M, G or T suffix, itself optionally followed by B, e.g. 16G, 64GB or
+// 1.5M. Returns None when invalid or too large.
+pub fn parse_bytes(value: &str, base: Base) -> Option<u64> {
+    let upper_value = value.trim().to_ascii_uppercase();
+    let number = upper_value.strip_suffix('B').unwrap_or(&upper_value);
+    let unit: f64 = match base {
+        Base::Decimal => 1000.0,
+        Base::Binary => 1024.0,
+    };
+    let (number, multiplier) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], unit),
+        Some('M') => (&number[..number.len() - 1], unit.powi(2)),
+        Some('G') => (&number[..number.len() - 1], unit.powi(3)),
+        Some('T') => (&number[..number.len() - 1], unit.powi(4)),
+        _ => (number, 1.0),
+    };
+    let bytes = number.trim().parse::<f64>().ok()? * multiplier;
+    // Conversion to integer saturates, silently turning huge values into u64::MAX
+    if !bytes.is_finite() || bytes < 0.0 || bytes >= u64::MAX as f64 {
+        return None;
+    }
+    Some(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffixes_follow_the_base() {
+        assert_eq!(parse_bytes("16G", Base::Decimal), Some(16_000_000_000));
+        assert_eq!(parse_bytes("16G", Base::Binary), Some(16 << 30));
+        assert_eq!(parse_bytes("500k", Base::Decimal), Some(500_000));
+        assert_eq!(parse_bytes("500k", Base::Binary), Some(500 << 10));
+        assert_eq!(parse_bytes("2M", Base::Binary), Some(2 << 20));
+        assert_eq!(parse_bytes("1T", Base::Decimal), Some(1_000_000_000_000));
+        assert_eq!(parse_bytes("1T", Base::Binary), Some(1 << 40));
+    }
+
+    #[test]
+    fn bytes_suffix_and_spaces_are_optional() {
+        assert_eq!(parse_bytes("64GB", Base::Decimal), Some(64_000_000_000));
+        assert_eq!(parse_bytes(" 64 gb ", Base::Decimal), Some(64_000_000_000));
+        assert_eq!(parse_bytes("1024", Base::Binary), Some(1024));
+        assert_eq!(parse_bytes("1024B", Base::Binary), Some(1024));
+    }
+
+    #[test]
+    fn fractions_are_rounded_down() {
+        assert_eq!(parse_bytes("1.5M", Base::Binary), Some(1536 << 10));
+        assert_eq!(parse_bytes("0.5", Base::Decimal), Some(0));
+        assert_eq!(parse_bytes("0", Base::Decimal), Some(0));
+    }
+
+    #[test]
+    fn invalid_values_are_refused() {
+        for value in [
+            "", "B", "K", "GB", "fast", "5X", "5BB", "-1M", "1.2.3", "NaN", "inf",
+        ] {
+            assert_eq!(parse_bytes(value, Base::Decimal), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn values_overflowing_are_refused() {
+        assert_eq!(parse_bytes("18446744073709551615", Base::Decimal), None);
+        assert_eq!(parse_bytes("20000000T", Base::Binary), None);
+        assert_eq!(
+            parse_bytes("16000000T", Base::Binary),
+            Some(16_000_000 << 40)
+        );
+    }
+}